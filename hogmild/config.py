@@ -4,6 +4,8 @@ import os
 SIMULATION = "simulation"
 # Number of samples to use for simulation only
 NUM_SAMPLES = "num_samples"
+# Whether to print the data associated with the data load or simulation
+PRINT_DATA = "print_data"
 
 # <<<< Common args across data sets and models >>>>
 # Model hyper parameter initial learning rate
@@ -14,7 +16,7 @@ DECAY_RATE = "decay_rate"
 MAX_EPOCH = "max_epoch"
 # When to stop training
 STOPPING_CRITERION = "stopping_criterion"
-# Whether or not to do hogwild
+# Train with real multi-threaded hogwild instead of replaying the simulation
 HOGWILD = "hogwild"
 # The model to use
 MODEL = "model"
@@ -68,6 +70,7 @@ N_MOVIES = "n_movies"
 HOGMILD_ARGS = [
     SIMULATION,
     NUM_SAMPLES,
+    PRINT_DATA,
     ALPHA_0,
     DECAY_RATE,
     MAX_EPOCH,
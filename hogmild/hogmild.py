@@ -1,10 +1,11 @@
+import json
 from subprocess import Popen, PIPE
 
 import config as cf
 
 
 def make_hogmild_args_list(config):
-    res = [cf.HOGMILD_PATH]
+    res = [cf.HOGMILD_PATH, "--output-format", "json"]
     for arg_name in cf.HOGMILD_ARGS:
         rust_flag = f"--{arg_name.replace('_', '-')}"
         arg_val = config[arg_name]
@@ -17,14 +18,11 @@ def make_hogmild_args_list(config):
     return res
 
 
-def run_hogmild(config) -> tuple[int, list[float]]:
+def run_hogmild(config) -> tuple[int | None, list[float]]:
     cmd = make_hogmild_args_list(config)
     process = Popen(cmd, stdout=PIPE)
 
     stdout, _ = process.communicate()
-    output = stdout.decode("utf-8").splitlines()
+    report = json.loads(stdout)
 
-    cycles = int(output[0].split(":")[1])
-    history = list(map(lambda x: float(x), output[1:]))
-
-    return cycles, history
+    return report["cycles"], report["losses"]
@@ -1,11 +1,69 @@
-use clap::Parser;
+use std::{
+    ffi::OsString,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
-use crate::simulator::Tick;
+use clap::{error::ErrorKind, ArgAction, CommandFactory, Parser, ValueEnum};
+use serde::Serialize;
 
-#[derive(Parser, Debug)]
+use crate::{
+    banks::{BankMapping, Partition},
+    compression::{CompressionConfig, CompressionMethod},
+    consistency::Consistency,
+    data_loader::{
+        netflix::NetflixConfig,
+        split::{SplitConfig, SplitStrategy},
+    },
+    delay::{read_histogram, DelayDistribution, DelayModel},
+    dispatch::DispatchStrategy,
+    mat_comp::MatrixCompletionConfig,
+    precision::{NumberFormat, PrecisionConfig, Rounding},
+    shuffle::{ShuffleConfig, ShuffleStrategy},
+    simulator::{SimConfig, Tick},
+    staleness::StalenessScaling,
+};
+
+/// How `main` reports the results of a run.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    /// Cycle count and losses as plain lines, printed as they are computed
+    Text,
+    /// A single JSON record
+    Json,
+    /// A header line and a single CSV record
+    Csv,
+}
+
+#[derive(Parser, Debug, Serialize)]
+#[command(args_override_self = true)]
 pub struct Args {
+    /// YAML or TOML file with default values, command line flags take priority.
+    /// Switches set to `true` in it are turned off again with `--switch=false`
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// Print the loaded data or the simulated update logs, to stderr unless
+    /// the output format is text
+    #[arg(long, default_value_t = false, num_args = 0..=1, require_equals = true,
+        default_missing_value = "true", action = ArgAction::Set)]
+    pub print_data: bool,
+    /// Write a Chrome/Perfetto trace of the (first) simulated schedule here
+    #[arg(long)]
+    pub trace: Option<PathBuf>,
+    /// Report how busy each simulated resource was and which one limits
+    /// throughput, for the (first) simulated schedule
+    #[arg(long, default_value_t = false, num_args = 0..=1, require_equals = true,
+        default_missing_value = "true", action = ArgAction::Set)]
+    pub utilization: bool,
+    /// Format of the results written to stdout
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub output_format: OutputFormat,
     /// Run the simulation, otherwise load the data
-    #[arg(long, default_value_t = false)]
+    #[arg(long, default_value_t = false, num_args = 0..=1, require_equals = true,
+        default_missing_value = "true", action = ArgAction::Set)]
     pub simulation: bool,
     /// Number of samples to use for simulation only
     #[arg(long, default_value_t = 128)]
@@ -24,8 +82,16 @@ pub struct Args {
     /// When to stop training
     #[arg(long, default_value_t = 0.001)]
     pub stopping_criterion: f32,
-    /// Whether or not to do hogwild
-    #[arg(long, default_value_t = false)]
+    /// How the learning rate of every replayed update is scaled by its
+    /// staleness
+    #[arg(long, value_enum, default_value_t = StalenessScaling::None)]
+    pub staleness_scaling: StalenessScaling,
+    /// Decay rate of exponential staleness scaling
+    #[arg(long, default_value_t = 0.1)]
+    pub staleness_decay: f32,
+    /// Train with real multi-threaded Hogwild! instead of replaying the simulation
+    #[arg(long, default_value_t = false, num_args = 0..=1, require_equals = true,
+        default_missing_value = "true", action = ArgAction::Set)]
     pub hogwild: bool,
     /// The model to use
     #[arg(long, default_value = "mat_comp")]
@@ -33,29 +99,95 @@ pub struct Args {
     /// The dataset to use
     #[arg(long, default_value = "netflix")]
     pub dataset: String,
-    /// RNG seed for weights initialization
+    /// RNG seed for weights initialization, data splitting, shuffling and
+    /// random simulation timing
     #[arg(long, default_value_t = 4102000)]
     pub rng_seed: u64,
+    /// Simulate a new schedule every epoch instead of replaying the first one
+    #[arg(long, default_value_t = false, num_args = 0..=1, require_equals = true,
+        default_missing_value = "true", action = ArgAction::Set)]
+    pub resimulate: bool,
+    /// Order in which samples are sent every epoch
+    #[arg(long, value_enum, default_value_t = ShuffleStrategy::None)]
+    pub shuffle: ShuffleStrategy,
+    /// Number of samples per block when shuffling blocks
+    #[arg(long, default_value_t = 1024)]
+    pub shuffle_block_size: usize,
+    /// How to hold out validation and test entries
+    #[arg(long, value_enum, default_value_t = SplitStrategy::None)]
+    pub split: SplitStrategy,
+    /// Fraction of the entries to use for validation
+    #[arg(long, default_value_t = 0.1, value_parser = unit_interval::<f32>)]
+    pub validation_fraction: f32,
+    /// Fraction of the entries to use for testing, leaving some for training
+    /// together with the validation fraction
+    #[arg(long, default_value_t = 0.1, value_parser = unit_interval::<f32>)]
+    pub test_fraction: f32,
     /// Number of banks to separate the weights into
-    #[arg(long, default_value_t = 8)]
+    #[arg(long, default_value_t = 8, value_parser = positive)]
     pub n_weight_banks: usize,
+    /// Which banks a sample reads, anything but a pool needs a data set
+    #[arg(long, value_enum, default_value_t = BankMapping::Pool)]
+    pub bank_mapping: BankMapping,
     /// Number of worker threads in async sgd
-    #[arg(long, default_value_t = 8)]
+    #[arg(long, default_value_t = 8, value_parser = positive)]
     pub n_workers: usize,
-    /// Number of gradient folds that can happen in parallel
-    #[arg(long, default_value_t = 8)]
+    /// Number of gradient folds that can happen in parallel, per shard
+    #[arg(long, default_value_t = 8, value_parser = positive)]
     pub n_folders: usize,
+    /// Number of parameter server shards, more than one needs a data set
+    #[arg(long, default_value_t = 1, value_parser = positive)]
+    pub n_shards: usize,
+    /// Which shard owns every user and movie row
+    #[arg(long, value_enum, default_value_t = Partition::Modulo)]
+    pub shard_mapping: Partition,
     /// Fifo depth in async sgd
-    #[arg(long, default_value_t = 8)]
+    #[arg(long, default_value_t = 8, value_parser = positive)]
     pub fifo_depth: usize,
+    /// Number of samples a worker takes in before sending their combined
+    /// update as one message
+    #[arg(long, default_value_t = 1, value_parser = positive)]
+    pub batch_size: usize,
+    /// How the parameter server picks the worker each sample is sent to,
+    /// conflict-aware needs a data set
+    #[arg(long, value_enum, default_value_t = DispatchStrategy::InOrder)]
+    pub dispatch: DispatchStrategy,
+    /// Hold back samples sharing a row or column with one in flight, sending
+    /// the first conflict free one among this many instead. 0 sends samples
+    /// in order. Needs a data set
+    #[arg(long, default_value_t = 0)]
+    pub conflict_lookahead: usize,
+    /// When the parameter server may send the next sample
+    #[arg(long, value_enum, default_value_t = Consistency::Async)]
+    pub consistency: Consistency,
+    /// Largest staleness of any sample with stale synchronous parallel,
+    /// counted in samples
+    #[arg(long, default_value_t = 16)]
+    pub staleness_bound: usize,
 
     // <<<< Timing Related >>>>
-    /// Time to send a sample/update
+    /// Time to send a sample or the update of a single sample, batch updates
+    /// take longer the more rows they change
     #[arg(long, default_value_t = 4)]
     pub send_delay: Tick,
-    /// Time to deliver a sample/update
+    /// Time to deliver a sample/update, the mean of the delay distributions
     #[arg(long, default_value_t = 8)]
     pub network_delay: Tick,
+    /// Distribution of the delay from the parameter server to the workers
+    #[arg(long, value_enum, default_value_t = DelayDistribution::Constant)]
+    pub downlink_delay: DelayDistribution,
+    /// Distribution of the delay from the workers to the parameter server
+    #[arg(long, value_enum, default_value_t = DelayDistribution::Constant)]
+    pub uplink_delay: DelayDistribution,
+    /// Half width of uniform network delays
+    #[arg(long, default_value_t = 0)]
+    pub network_delay_spread: Tick,
+    /// Histogram file of empirical downlink delays, `delay weight` per line
+    #[arg(long)]
+    pub downlink_delay_histogram: Option<PathBuf>,
+    /// Histogram file of empirical uplink delays, `delay weight` per line
+    #[arg(long)]
+    pub uplink_delay_histogram: Option<PathBuf>,
     /// Time to receive a sample/update
     #[arg(long, default_value_t = 4)]
     pub receive_delay: Tick,
@@ -71,6 +203,27 @@ pub struct Args {
     /// Latency of folding one gradient update
     #[arg(long, default_value_t = 32)]
     pub fold_latency: Tick,
+    /// Per worker initiation interval of gradient calculation, one per
+    /// worker, overrides gradient_ii
+    #[arg(long, value_delimiter = ',', action = ArgAction::Set)]
+    pub worker_gradient_ii: Vec<Tick>,
+    /// Per worker latency of calculating one gradient, one per worker,
+    /// overrides gradient_latency
+    #[arg(long, value_delimiter = ',', action = ArgAction::Set)]
+    pub worker_gradient_latency: Vec<Tick>,
+    /// Scale every worker's gradient latency by a random factor in
+    /// [1 - spread, 1 + spread]
+    #[arg(long, default_value_t = 0., value_parser = unit_interval::<f64>)]
+    pub gradient_latency_spread: f64,
+    /// Maximum random extra latency of every gradient
+    #[arg(long, default_value_t = 0)]
+    pub gradient_jitter: Tick,
+    /// Probability that a gradient straggles
+    #[arg(long, default_value_t = 0., value_parser = unit_interval::<f64>)]
+    pub straggler_prob: f64,
+    /// Factor by which a straggling gradient's latency is multiplied
+    #[arg(long, default_value_t = 4.)]
+    pub straggler_slowdown: f64,
 
     // <<<< Matrix completion specific >>>>
     /// Number of features in the decomposition matrix
@@ -91,9 +244,602 @@ pub struct Args {
     /// Model hyper parameter lambda_yb
     #[arg(long, default_value_t = 1.)]
     pub lam_yb: f32,
+    /// How the workers compress their updates, which also shortens the time
+    /// to send them
+    #[arg(long, value_enum, default_value_t = CompressionMethod::None)]
+    pub compression: CompressionMethod,
+    /// Number of values kept per row by top-k compression
+    #[arg(long, default_value_t = 2)]
+    pub compression_top_k: usize,
+    /// Drop what compression loses instead of adding it to the next update
+    /// of the same row
+    #[arg(long, default_value_t = false, num_args = 0..=1, require_equals = true,
+        default_missing_value = "true", action = ArgAction::Set)]
+    pub no_error_feedback: bool,
+    /// Number format of the weights the workers read
+    #[arg(long, value_enum, default_value_t = NumberFormat::F32)]
+    pub weight_format: NumberFormat,
+    /// Number format of the gradient updates
+    #[arg(long, value_enum, default_value_t = NumberFormat::F32)]
+    pub gradient_format: NumberFormat,
+    /// Number format of the weights the updates are folded into
+    #[arg(long, value_enum, default_value_t = NumberFormat::F32)]
+    pub accumulation_format: NumberFormat,
+    /// Integer bits of the fixed point format, not counting the sign
+    #[arg(long, default_value_t = 7)]
+    pub fixed_int_bits: u32,
+    /// Fraction bits of the fixed point format
+    #[arg(long, default_value_t = 8)]
+    pub fixed_frac_bits: u32,
+    /// How values are rounded to the reduced precision formats
+    #[arg(long, value_enum, default_value_t = Rounding::Nearest)]
+    pub rounding: Rounding,
 
     // <<<< Netflix dataset specific >>>>
     /// Number of movies to load
     #[arg(short, long, default_value_t = 100)]
     pub n_movies: usize,
 }
+
+impl Args {
+    /// Parse the command line, filling in values from `--config` if given.
+    /// Exits the process with a usage error on failure.
+    pub fn load() -> Self {
+        Self::try_load_from(std::env::args_os()).unwrap_or_else(|e| e.exit())
+    }
+
+    pub fn try_load_from<I, T>(itr: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let cli: Vec<OsString> = itr.into_iter().map(Into::into).collect();
+        let mut args = Self::try_parse_from(&cli)?;
+        if let Some(path) = &args.config {
+            // File values go first so that flags given on the command line
+            // override them.
+            let mut argv = cli[..1].to_vec();
+            argv.extend(config_file_args(path)?);
+            argv.extend_from_slice(&cli[1..]);
+            args = Self::try_parse_from(argv)?;
+        }
+        args.validate()?;
+        Ok(args)
+    }
+
+    /// Reject combinations of flags that parse but cannot run.
+    fn validate(&self) -> Result<(), clap::Error> {
+        let conflict = |msg: &str| Err(Args::command().error(ErrorKind::ArgumentConflict, msg));
+        // The simulated samples have no matrix coordinates
+        let stratified = matches!(
+            self.shuffle,
+            ShuffleStrategy::ByRow | ShuffleStrategy::ByColumn
+        );
+        if self.simulation && stratified {
+            return conflict("--shuffle by-row and by-column need a dataset, not --simulation");
+        }
+        if self.simulation && self.dispatch == DispatchStrategy::ConflictAware {
+            return conflict("--dispatch conflict-aware needs a dataset, not --simulation");
+        }
+        if self.simulation && self.bank_mapping != BankMapping::Pool {
+            return conflict("--bank-mapping other than pool needs a dataset, not --simulation");
+        }
+        if self.simulation && self.n_shards > 1 {
+            return conflict("--n-shards above 1 needs a dataset, not --simulation");
+        }
+        if self.simulation && self.conflict_lookahead > 0 {
+            return conflict("--conflict-lookahead above 0 needs a dataset, not --simulation");
+        }
+        // Hogwild! threads have no simulated staleness to scale by
+        if self.hogwild && self.staleness_scaling != StalenessScaling::None {
+            return conflict(
+                "--staleness-scaling only applies to replayed schedules, not --hogwild",
+            );
+        }
+        let per_worker = [
+            ("--worker-gradient-ii", &self.worker_gradient_ii),
+            ("--worker-gradient-latency", &self.worker_gradient_latency),
+        ];
+        for (flag, list) in per_worker {
+            if !list.is_empty() && list.len() != self.n_workers {
+                return conflict(&format!("{flag} must have one entry per worker"));
+            }
+        }
+        if self.validation_fraction + self.test_fraction >= 1. {
+            return conflict(
+                "--validation-fraction and --test-fraction must leave entries to train on",
+            );
+        }
+        if self.fixed_int_bits.saturating_add(self.fixed_frac_bits) > 31 {
+            return conflict("the fixed point format must fit in 32 bits with its sign");
+        }
+        // Checked here rather than with `requires_if`, which would not see
+        // a histogram set in the config file
+        let histograms = [
+            (
+                "downlink",
+                self.downlink_delay,
+                &self.downlink_delay_histogram,
+            ),
+            ("uplink", self.uplink_delay, &self.uplink_delay_histogram),
+        ];
+        for (direction, distribution, histogram) in histograms {
+            match (distribution, histogram) {
+                (DelayDistribution::Empirical, Some(path)) => {
+                    read_histogram(path).map_err(|e| {
+                        let msg = format!("delay histogram {}: {}", path.display(), e);
+                        Args::command().error(ErrorKind::Io, msg)
+                    })?;
+                }
+                (DelayDistribution::Empirical, None) => {
+                    let msg = format!(
+                        "--{direction}-delay empirical needs --{direction}-delay-histogram"
+                    );
+                    return Err(Args::command().error(ErrorKind::MissingRequiredArgument, msg));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A count that must be at least 1 for the simulation to make progress.
+fn positive(s: &str) -> Result<usize, String> {
+    match s.parse().map_err(|e| format!("{e}"))? {
+        0 => Err("must be at least 1".to_string()),
+        n => Ok(n),
+    }
+}
+
+/// A number in [0, 1], such as a probability.
+fn unit_interval<T>(s: &str) -> Result<T, String>
+where
+    T: FromStr + PartialOrd + From<u8> + Display,
+    T::Err: Display,
+{
+    let x: T = s.parse().map_err(|e| format!("{e}"))?;
+    if (T::from(0)..=T::from(1)).contains(&x) {
+        Ok(x)
+    } else {
+        Err(format!("{x} is not in [0, 1]"))
+    }
+}
+
+fn config_error(path: &Path, msg: impl std::fmt::Display) -> clap::Error {
+    Args::command().error(
+        ErrorKind::InvalidValue,
+        format!("config file {}: {}", path.display(), msg),
+    )
+}
+
+/// A scalar as a string, or a list of scalars joined with `,`.
+fn yaml_to_arg(val: serde_yaml::Value) -> Option<String> {
+    match val {
+        serde_yaml::Value::Bool(b) => Some(b.to_string()),
+        serde_yaml::Value::Number(n) => Some(n.to_string()),
+        serde_yaml::Value::String(s) => Some(s),
+        serde_yaml::Value::Sequence(vals) => {
+            let vals = vals.into_iter().map(|v| match v {
+                serde_yaml::Value::Sequence(_) => None,
+                v => yaml_to_arg(v),
+            });
+            Some(vals.collect::<Option<Vec<_>>>()?.join(","))
+        }
+        _ => None,
+    }
+}
+
+/// A scalar as a string, or a list of scalars joined with `,`.
+fn toml_to_arg(val: toml::Value) -> Option<String> {
+    match val {
+        toml::Value::Boolean(b) => Some(b.to_string()),
+        toml::Value::Integer(i) => Some(i.to_string()),
+        toml::Value::Float(f) => Some(f.to_string()),
+        toml::Value::String(s) => Some(s),
+        toml::Value::Array(vals) => {
+            let vals = vals.into_iter().map(|v| match v {
+                toml::Value::Array(_) => None,
+                v => toml_to_arg(v),
+            });
+            Some(vals.collect::<Option<Vec<_>>>()?.join(","))
+        }
+        _ => None,
+    }
+}
+
+/// Read a config file into `(key, value)` pairs, scalars rendered as strings
+/// and lists as comma separated strings.
+fn read_config_file(path: &Path) -> Result<Vec<(String, String)>, clap::Error> {
+    let text = fs::read_to_string(path).map_err(|e| config_error(path, e))?;
+    let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    match ext {
+        "yaml" | "yml" => {
+            let map: serde_yaml::Mapping =
+                serde_yaml::from_str(&text).map_err(|e| config_error(path, e))?;
+            map.into_iter()
+                .map(|(k, v)| {
+                    let key = k
+                        .as_str()
+                        .ok_or_else(|| config_error(path, "keys must be strings"))?
+                        .to_string();
+                    let val = yaml_to_arg(v)
+                        .ok_or_else(|| config_error(path, format!("{key} is not a scalar")))?;
+                    Ok((key, val))
+                })
+                .collect()
+        }
+        "toml" => {
+            let table: toml::Table = text.parse().map_err(|e| config_error(path, e))?;
+            table
+                .into_iter()
+                .map(|(key, v)| {
+                    let val = toml_to_arg(v)
+                        .ok_or_else(|| config_error(path, format!("{key} is not a scalar")))?;
+                    Ok((key, val))
+                })
+                .collect()
+        }
+        _ => Err(config_error(path, "expected a .yaml, .yml or .toml file")),
+    }
+}
+
+/// Translate a config file into the equivalent command line flags.
+fn config_file_args(path: &Path) -> Result<Vec<OsString>, clap::Error> {
+    let cmd = Args::command();
+    let mut argv = vec![];
+    for (key, val) in read_config_file(path)? {
+        let arg = cmd
+            .get_arguments()
+            .find(|a| a.get_id() == key.as_str() && a.get_id() != "config")
+            .ok_or_else(|| config_error(path, format!("unknown key {key}")))?;
+        let flag = format!("--{}", arg.get_long().unwrap());
+
+        // A single `--flag=value` so that negative numbers are not taken
+        // for flags. Switches accept `true` or `false` this way too.
+        argv.push(format!("{flag}={val}").into());
+    }
+    Ok(argv)
+}
+
+/// The delay model of one direction, `histogram` having been read once by
+/// `Args::validate` already.
+fn delay_model(
+    distribution: DelayDistribution,
+    spread: Tick,
+    histogram: &Option<PathBuf>,
+) -> DelayModel {
+    let histogram = match (distribution, histogram) {
+        (DelayDistribution::Empirical, Some(path)) => {
+            read_histogram(path).unwrap_or_else(|e| panic!("cannot read {}: {}", path.display(), e))
+        }
+        _ => vec![],
+    };
+    DelayModel {
+        distribution,
+        spread,
+        histogram,
+    }
+}
+
+impl From<&Args> for SimConfig {
+    fn from(args: &Args) -> Self {
+        Self {
+            n_weight_banks: args.n_weight_banks,
+            bank_mapping: args.bank_mapping,
+            n_workers: args.n_workers,
+            n_folders: args.n_folders,
+            n_shards: args.n_shards,
+            shard_mapping: args.shard_mapping,
+            fifo_depth: args.fifo_depth,
+            batch_size: args.batch_size,
+            dispatch: args.dispatch,
+            conflict_lookahead: args.conflict_lookahead,
+            consistency: args.consistency,
+            staleness_bound: args.staleness_bound,
+            send_delay: args.send_delay,
+            // Every row is sent along with its bias
+            update_size: CompressionConfig::from(args).update_size(args.n_features + 1),
+            network_delay: args.network_delay,
+            downlink_delay: delay_model(
+                args.downlink_delay,
+                args.network_delay_spread,
+                &args.downlink_delay_histogram,
+            ),
+            uplink_delay: delay_model(
+                args.uplink_delay,
+                args.network_delay_spread,
+                &args.uplink_delay_histogram,
+            ),
+            receive_delay: args.receive_delay,
+            gradient_ii: args.gradient_ii,
+            gradient_latency: args.gradient_latency,
+            fold_ii: args.fold_ii,
+            fold_latency: args.fold_latency,
+            worker_gradient_ii: args.worker_gradient_ii.clone(),
+            worker_gradient_latency: args.worker_gradient_latency.clone(),
+            gradient_latency_spread: args.gradient_latency_spread,
+            gradient_jitter: args.gradient_jitter,
+            straggler_prob: args.straggler_prob,
+            straggler_slowdown: args.straggler_slowdown,
+            seed: args.rng_seed,
+            epoch: 0,
+        }
+    }
+}
+
+impl From<&Args> for MatrixCompletionConfig {
+    fn from(args: &Args) -> Self {
+        Self {
+            alpha_0: args.alpha_0,
+            decay_rate: args.decay_rate,
+            max_epoch: args.max_epoch,
+            stopping_criterion: args.stopping_criterion,
+            rng_seed: args.rng_seed,
+            staleness_scaling: args.staleness_scaling,
+            staleness_decay: args.staleness_decay,
+            n_features: args.n_features,
+            mu: args.mu,
+            lam_xf: args.lam_xf,
+            lam_yf: args.lam_yf,
+            lam_xb: args.lam_xb,
+            lam_yb: args.lam_yb,
+        }
+    }
+}
+
+impl From<&Args> for SplitConfig {
+    fn from(args: &Args) -> Self {
+        Self {
+            strategy: args.split,
+            validation_fraction: args.validation_fraction,
+            test_fraction: args.test_fraction,
+            seed: args.rng_seed,
+        }
+    }
+}
+
+impl From<&Args> for ShuffleConfig {
+    fn from(args: &Args) -> Self {
+        Self {
+            strategy: args.shuffle,
+            block_size: args.shuffle_block_size,
+            seed: args.rng_seed,
+        }
+    }
+}
+
+impl From<&Args> for CompressionConfig {
+    fn from(args: &Args) -> Self {
+        Self {
+            method: args.compression,
+            top_k: args.compression_top_k,
+            error_feedback: !args.no_error_feedback,
+        }
+    }
+}
+
+impl From<&Args> for PrecisionConfig {
+    fn from(args: &Args) -> Self {
+        Self {
+            weights: args.weight_format,
+            int_bits: args.fixed_int_bits,
+            frac_bits: args.fixed_frac_bits,
+            rounding: args.rounding,
+        }
+    }
+}
+
+impl From<&Args> for NetflixConfig {
+    fn from(args: &Args) -> Self {
+        Self {
+            n_movies: args.n_movies,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write `text` to a file named `name` in a fresh temporary directory.
+    fn config_file(name: &str, text: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hogmild-args-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn load(path: &Path, flags: &[&str]) -> Result<Args, clap::Error> {
+        let config = format!("--config={}", path.display());
+        let argv = ["hogmild", config.as_str()]
+            .into_iter()
+            .chain(flags.iter().copied());
+        Args::try_load_from(argv)
+    }
+
+    #[test]
+    fn command_line_overrides_config_file() {
+        let path = config_file(
+            "merge.yaml",
+            "mu: -0.5\nn_workers: 3\nsimulation: true\nresimulate: false\n",
+        );
+        let args = load(&path, &[]).unwrap();
+        assert_eq!(args.mu, -0.5);
+        assert_eq!(args.n_workers, 3);
+        assert!(args.simulation);
+        assert!(!args.resimulate);
+
+        let args = load(
+            &path,
+            &["--n-workers", "5", "--simulation=false", "--resimulate"],
+        )
+        .unwrap();
+        assert_eq!(args.mu, -0.5);
+        assert_eq!(args.n_workers, 5);
+        assert!(!args.simulation);
+        assert!(args.resimulate);
+    }
+
+    #[test]
+    fn command_line_replaces_config_file_lists() {
+        let path = config_file(
+            "lists.yaml",
+            "n_workers: 2\nworker_gradient_ii: [8, 16]\nworker_gradient_latency: [40, 80]\n",
+        );
+        let args = load(&path, &["--worker-gradient-ii", "4,4"]).unwrap();
+        assert_eq!(args.worker_gradient_ii, [4, 4]);
+        assert_eq!(args.worker_gradient_latency, [40, 80]);
+
+        // Repeating the flag replaces the list too
+        let argv = [
+            "hogmild",
+            "--n-workers=2",
+            "--worker-gradient-ii=1,2",
+            "--worker-gradient-ii=3,4",
+        ];
+        assert_eq!(
+            Args::try_load_from(argv).unwrap().worker_gradient_ii,
+            [3, 4]
+        );
+    }
+
+    #[test]
+    fn per_worker_lists_need_one_entry_per_worker() {
+        for flag in ["--worker-gradient-ii", "--worker-gradient-latency"] {
+            let argv = ["hogmild", "--simulation", "--n-workers=3", flag, "4,4"];
+            let err = Args::try_load_from(argv).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::ArgumentConflict, "{flag}");
+            let argv = ["hogmild", "--simulation", "--n-workers=2", flag, "4,4"];
+            assert!(Args::try_load_from(argv).is_ok());
+        }
+    }
+
+    #[test]
+    fn toml_config_file() {
+        let path = config_file("config.toml", "mu = -0.25\nhogwild = true\nn_workers = 2\n");
+        let args = load(&path, &[]).unwrap();
+        assert_eq!(args.mu, -0.25);
+        assert!(args.hogwild);
+        assert_eq!(args.n_workers, 2);
+    }
+
+    #[test]
+    fn simulation_rejects_what_needs_a_dataset() {
+        let parse = |flags: &[&str]| {
+            let argv = ["hogmild", "--simulation"]
+                .into_iter()
+                .chain(flags.iter().copied());
+            Args::try_load_from(argv).map_err(|e| e.kind())
+        };
+        assert!(parse(&[]).is_ok());
+        for flags in [
+            ["--shuffle", "by-row"],
+            ["--shuffle", "by-column"],
+            ["--dispatch", "conflict-aware"],
+            ["--bank-mapping", "modulo"],
+            ["--bank-mapping", "hashed"],
+            ["--bank-mapping", "range"],
+            ["--n-shards", "2"],
+            ["--conflict-lookahead", "8"],
+        ] {
+            assert_eq!(parse(&flags).unwrap_err(), ErrorKind::ArgumentConflict);
+        }
+    }
+
+    #[test]
+    fn fractions_must_be_in_the_unit_interval() {
+        let parse =
+            |flag: &str, val: &str| Args::try_load_from(["hogmild", &format!("{flag}={val}")]);
+        let flags = [
+            "--straggler-prob",
+            "--gradient-latency-spread",
+            "--validation-fraction",
+            "--test-fraction",
+        ];
+        for flag in flags {
+            assert!(parse(flag, "0").is_ok() && parse(flag, "0.5").is_ok());
+            for val in ["2", "-0.1", "NaN", "x"] {
+                let err = parse(flag, val).unwrap_err();
+                assert_eq!(err.kind(), ErrorKind::ValueValidation, "{flag} {val}");
+            }
+        }
+        assert!(parse("--straggler-prob", "1").is_ok());
+        // Nothing would be left to train on
+        let flags = [
+            "hogmild",
+            "--validation-fraction=0.6",
+            "--test-fraction=0.4",
+        ];
+        let err = Args::try_load_from(flags).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn empirical_delays_need_a_valid_histogram() {
+        let parse = |flags: &[&str]| {
+            let argv = ["hogmild"].into_iter().chain(flags.iter().copied());
+            Args::try_load_from(argv).map_err(|e| e.kind())
+        };
+        for direction in ["downlink", "uplink"] {
+            let delay = format!("--{direction}-delay=empirical");
+            let flag = format!("--{direction}-delay-histogram");
+            assert_eq!(
+                parse(&[&delay]).unwrap_err(),
+                ErrorKind::MissingRequiredArgument
+            );
+            let good = config_file("good.hist", "# delay weight\n3 1\n9, 2.5\n\n12\n");
+            assert!(parse(&[&delay, &flag, good.to_str().unwrap()]).is_ok());
+            for bad in ["3 x\n", "0 0\n", "", "3 -1\n"] {
+                let bad = config_file("bad.hist", bad);
+                let flags = [delay.as_str(), &flag, bad.to_str().unwrap()];
+                assert_eq!(parse(&flags).unwrap_err(), ErrorKind::Io);
+            }
+            let missing = "/nonexistent/delays.hist";
+            assert_eq!(parse(&[&delay, &flag, missing]).unwrap_err(), ErrorKind::Io);
+
+            // The histogram can come from the config file
+            let key = format!("{direction}_delay_histogram");
+            let path = config_file(
+                &format!("{direction}.yaml"),
+                &format!("{key}: {}\n", good.display()),
+            );
+            assert!(load(&path, &[&delay]).is_ok());
+        }
+    }
+
+    #[test]
+    fn counts_must_be_positive() {
+        let flags = [
+            "--n-weight-banks",
+            "--n-workers",
+            "--n-folders",
+            "--n-shards",
+            "--fifo-depth",
+            "--batch-size",
+        ];
+        for flag in flags {
+            let err = Args::try_load_from(["hogmild", flag, "0"]).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::ValueValidation, "{flag}");
+            assert!(Args::try_load_from(["hogmild", flag, "2"]).is_ok());
+        }
+    }
+
+    #[test]
+    fn hogwild_has_no_staleness_to_scale() {
+        let flags = ["hogmild", "--hogwild", "--staleness-scaling", "inverse"];
+        let err = Args::try_load_from(flags).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ArgumentConflict);
+        let flags = ["hogmild", "--hogwild", "--staleness-scaling", "none"];
+        assert!(Args::try_load_from(flags).is_ok());
+    }
+
+    #[test]
+    fn unknown_config_key() {
+        let path = config_file("unknown.yaml", "n_wrokers: 3\n");
+        let err = load(&path, &[]).unwrap_err();
+        assert!(err.to_string().contains("unknown key n_wrokers"));
+    }
+}
@@ -0,0 +1,164 @@
+use std::fmt;
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::data_structures::CoordListSparseMatrix;
+
+/// Which weight banks sending a sample reads. The user rows are laid out
+/// first in the weight memory, followed by the movie rows.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BankMapping {
+    /// Any free bank serves any sample
+    Pool,
+    /// Row `r` lives in bank `r % n_weight_banks`
+    Modulo,
+    /// Row `r` lives in the bank its hash selects
+    Hashed,
+    /// Contiguous ranges of rows live in the same bank
+    Range,
+}
+
+/// How the rows of the weight memory are spread over banks or parameter
+/// server shards. The user rows are laid out first, followed by the movie
+/// rows.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Partition {
+    /// Row `r` lives in part `r % n_parts`
+    Modulo,
+    /// Row `r` lives in the part its hash selects
+    Hashed,
+    /// Contiguous ranges of rows live in the same part
+    Range,
+}
+
+impl BankMapping {
+    /// How the rows are spread over the banks, `None` for a pool.
+    pub fn partition(self) -> Option<Partition> {
+        match self {
+            BankMapping::Pool => None,
+            BankMapping::Modulo => Some(Partition::Modulo),
+            BankMapping::Hashed => Some(Partition::Hashed),
+            BankMapping::Range => Some(Partition::Range),
+        }
+    }
+}
+
+/// How the weight banks held back the samples.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct BankStats {
+    /// Number of samples that found a bank they read busy while a worker
+    /// had room for them
+    pub stalled: usize,
+    /// Number of samples whose user and movie rows share a bank, read one
+    /// after the other
+    pub serialized: usize,
+}
+
+impl BankStats {
+    /// Accumulate the counts of another schedule.
+    pub fn add(&mut self, other: &BankStats) {
+        self.stalled += other.stalled;
+        self.serialized += other.serialized;
+    }
+}
+
+impl fmt::Display for BankStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "stalled: {}, serialized: {}",
+            self.stalled, self.serialized
+        )
+    }
+}
+
+fn part_of(partition: Partition, row: usize, n_memory_rows: usize, n_parts: usize) -> usize {
+    match partition {
+        Partition::Modulo => row % n_parts,
+        // Fibonacci hashing, the part comes from the top bits, which are
+        // the best mixed
+        Partition::Hashed => {
+            let hash = (row as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+            ((hash as u128 * n_parts as u128) >> 64) as usize
+        }
+        Partition::Range => row * n_parts / n_memory_rows.max(1),
+    }
+}
+
+/// The parts of the user and movie rows of every sample of `matrix`.
+pub(crate) fn partition_rows(
+    partition: Partition,
+    matrix: &CoordListSparseMatrix<f32>,
+    n_parts: usize,
+) -> Vec<(usize, usize)> {
+    let n_users = matrix.n_rows();
+    let n_memory_rows = n_users + matrix.n_cols();
+    matrix
+        .iter()
+        .map(|&(i, j, _)| {
+            (
+                part_of(partition, i, n_memory_rows, n_parts),
+                part_of(partition, n_users + j, n_memory_rows, n_parts),
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parts of every memory row.
+    fn parts(partition: Partition, n_memory_rows: usize, n_parts: usize) -> Vec<usize> {
+        (0..n_memory_rows)
+            .map(|r| part_of(partition, r, n_memory_rows, n_parts))
+            .collect()
+    }
+
+    #[test]
+    fn modulo_interleaves_rows() {
+        assert_eq!(parts(Partition::Modulo, 6, 4), [0, 1, 2, 3, 0, 1]);
+    }
+
+    #[test]
+    fn range_keeps_rows_contiguous() {
+        let parts = parts(Partition::Range, 10, 4);
+        assert_eq!(parts, [0, 0, 0, 1, 1, 2, 2, 2, 3, 3]);
+        assert_eq!(self::parts(Partition::Range, 3, 3), [0, 1, 2]);
+    }
+
+    #[test]
+    fn hashed_spreads_rows_evenly() {
+        let parts = parts(Partition::Hashed, 1000, 4);
+        assert_eq!(parts, self::parts(Partition::Hashed, 1000, 4));
+        for part in 0..4 {
+            let n = parts.iter().filter(|&&p| p == part).count();
+            assert!((200..300).contains(&n), "part {part} has {n} rows");
+        }
+        // Unlike modulo, rows strided by a power of two are spread too
+        for stride in [4, 8, 16] {
+            let strided: Vec<_> = (0..40)
+                .map(|r| part_of(Partition::Hashed, stride * r, 640, 4))
+                .collect();
+            assert!((0..4).all(|p| strided.contains(&p)), "stride {stride}");
+        }
+    }
+
+    #[test]
+    fn movie_rows_follow_the_user_rows() {
+        // Three users and two movies, memory rows 0-2 and 3-4
+        let mut matrix = CoordListSparseMatrix::new_empty();
+        (0..3).for_each(|_| matrix.add_row());
+        (0..2).for_each(|_| matrix.add_col());
+        for (i, j) in [(0, 0), (1, 1), (2, 0)] {
+            matrix.insert(i, j, 1.);
+        }
+        let modulo = partition_rows(Partition::Modulo, &matrix, 2);
+        assert_eq!(modulo, [(0, 1), (1, 0), (0, 1)]);
+        let range = partition_rows(Partition::Range, &matrix, 3);
+        assert_eq!(range, [(0, 1), (0, 2), (1, 1)]);
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+/// How the workers compress the updates they send.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CompressionMethod {
+    /// Full f32 updates
+    None,
+    /// Keep the `top_k` largest values of every row, sent with their indices
+    TopK,
+    /// 8 bit integers scaled by the largest value of every row
+    Int8,
+    /// 16 bit integers scaled by the largest value of every row
+    Int16,
+    /// The sign of every value scaled by the mean magnitude of the row
+    Sign,
+}
+
+#[derive(Clone, Debug)]
+pub struct CompressionConfig {
+    pub method: CompressionMethod,
+    /// Number of values kept per row by `CompressionMethod::TopK`
+    pub top_k: usize,
+    /// Add what compression dropped from a row to the next update of that
+    /// row from the same worker
+    pub error_feedback: bool,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            method: CompressionMethod::None,
+            top_k: 2,
+            error_feedback: true,
+        }
+    }
+}
+
+impl CompressionConfig {
+    /// Size of a compressed row of `n_values` relative to the f32 one.
+    pub fn update_size(&self, n_values: usize) -> f64 {
+        let n = n_values.max(1) as f64;
+        match self.method {
+            CompressionMethod::None => 1.,
+            // A 32 bit index along every value kept
+            CompressionMethod::TopK => (2. * self.top_k as f64 / n).min(1.),
+            // Plus the f32 scale
+            CompressionMethod::Int8 => (8. * n + 32.) / (32. * n),
+            CompressionMethod::Int16 => (16. * n + 32.) / (32. * n),
+            CompressionMethod::Sign => (n + 32.) / (32. * n),
+        }
+    }
+}
+
+/// Compresses the rows a worker sends, keeping the error of every row when
+/// error feedback is on.
+pub(crate) struct Compressor {
+    config: CompressionConfig,
+    /// What compression dropped from every row so far
+    residuals: HashMap<usize, Vec<f32>>,
+}
+
+impl Compressor {
+    pub(crate) fn new(config: &CompressionConfig) -> Self {
+        Self {
+            config: config.clone(),
+            residuals: HashMap::new(),
+        }
+    }
+
+    /// Replace `values`, the update of the row `key`, by what the worker
+    /// sends.
+    pub(crate) fn compress(&mut self, key: usize, values: &mut [f32]) {
+        if self.config.method == CompressionMethod::None {
+            return;
+        }
+        let feedback = self.config.error_feedback;
+        if feedback {
+            if let Some(residual) = self.residuals.get(&key) {
+                values.iter_mut().zip(residual).for_each(|(v, r)| *v += r);
+            }
+        }
+        let wanted = values.to_vec();
+        match self.config.method {
+            CompressionMethod::None => {}
+            CompressionMethod::TopK => top_k(values, self.config.top_k),
+            CompressionMethod::Int8 => quantize(values, 8),
+            CompressionMethod::Int16 => quantize(values, 16),
+            CompressionMethod::Sign => sign(values),
+        }
+        if feedback {
+            let residual = wanted.iter().zip(&*values).map(|(w, v)| w - v).collect();
+            self.residuals.insert(key, residual);
+        }
+    }
+}
+
+fn top_k(values: &mut [f32], k: usize) {
+    if k >= values.len() {
+        return;
+    }
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    order.select_nth_unstable_by(k, |&a, &b| values[b].abs().total_cmp(&values[a].abs()));
+    for &i in &order[k..] {
+        values[i] = 0.;
+    }
+}
+
+fn quantize(values: &mut [f32], bits: u32) {
+    let levels = ((1 << (bits - 1)) - 1) as f32;
+    let max = values.iter().fold(0f32, |m, v| m.max(v.abs()));
+    if max == 0. {
+        return;
+    }
+    let scale = max / levels;
+    values
+        .iter_mut()
+        .for_each(|v| *v = (*v / scale).round() * scale);
+}
+
+fn sign(values: &mut [f32]) {
+    let mean = values.iter().map(|v| v.abs()).sum::<f32>() / values.len().max(1) as f32;
+    values.iter_mut().for_each(|v| {
+        if *v != 0. {
+            *v = v.signum() * mean;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compressor(method: CompressionMethod, error_feedback: bool) -> Compressor {
+        Compressor::new(&CompressionConfig {
+            method,
+            top_k: 2,
+            error_feedback,
+        })
+    }
+
+    #[test]
+    fn top_k_keeps_the_largest_magnitudes() {
+        let mut values = [0.5, -3., 1., 2., -0.1];
+        top_k(&mut values, 2);
+        assert_eq!(values, [0., -3., 0., 2., 0.]);
+    }
+
+    #[test]
+    fn quantize_to_integer_levels() {
+        let mut values = [1.27, -0.5, 0.011, 0.];
+        quantize(&mut values, 8);
+        let scale = 1.27 / 127.;
+        assert_eq!(values[0], 1.27);
+        for (v, w) in values.iter().zip([1.27, -0.5, 0.011, 0.]) {
+            assert!((v / scale - (v / scale).round()).abs() < 1e-3);
+            assert!((v - w).abs() <= scale / 2.);
+        }
+        let mut values = [1.27, -0.5, 0.011, 0.];
+        quantize(&mut values, 16);
+        assert!((values[2] - 0.011).abs() <= 1.27 / 32767. / 2.);
+    }
+
+    #[test]
+    fn sign_scales_by_the_mean_magnitude() {
+        let mut values = [1., -3., 0., 4.];
+        sign(&mut values);
+        assert_eq!(values, [2., -2., 0., 2.]);
+    }
+
+    #[test]
+    fn error_feedback_adds_the_residual_to_the_next_update_of_the_row() {
+        let mut c = compressor(CompressionMethod::TopK, true);
+        let mut first = [3., 1., 0.5];
+        c.compress(7, &mut first);
+        assert_eq!(first, [3., 1., 0.]);
+        assert_eq!(c.residuals[&7], [0., 0., 0.5]);
+
+        // Another row has its own residual
+        let mut other = [0., 0., 0.];
+        c.compress(8, &mut other);
+        assert_eq!(other, [0., 0., 0.]);
+
+        let mut second = [0., 0.2, 0.];
+        c.compress(7, &mut second);
+        assert_eq!(second, [0., 0.2, 0.5]);
+        assert_eq!(c.residuals[&7], [0., 0., 0.]);
+    }
+
+    #[test]
+    fn without_error_feedback_the_loss_is_dropped() {
+        let mut c = compressor(CompressionMethod::TopK, false);
+        let mut first = [3., 1., 0.5];
+        c.compress(7, &mut first);
+        let mut second = [0., 0.2, 0.];
+        c.compress(7, &mut second);
+        assert_eq!(second, [0., 0.2, 0.]);
+        assert!(c.residuals.is_empty());
+    }
+
+    #[test]
+    fn update_sizes() {
+        let config = |method| CompressionConfig {
+            method,
+            ..Default::default()
+        };
+        assert_eq!(config(CompressionMethod::None).update_size(11), 1.);
+        assert_eq!(config(CompressionMethod::TopK).update_size(8), 0.5);
+        assert_eq!(config(CompressionMethod::TopK).update_size(2), 1.);
+        assert_eq!(config(CompressionMethod::Int8).update_size(4), 0.5);
+        assert_eq!(config(CompressionMethod::Sign).update_size(32), 2. / 32.);
+    }
+}
@@ -0,0 +1,200 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+};
+
+use serde::Serialize;
+
+use crate::{
+    data_structures::CoordListSparseMatrix,
+    simulator::{Tick, UpdatePart},
+};
+
+/// Increment or decrement the count of `key`, dropping it at zero.
+pub(crate) fn bump(counts: &mut HashMap<usize, usize>, key: usize, add: bool) {
+    let count = counts.entry(key).or_default();
+    if add {
+        *count += 1;
+    } else {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(&key);
+        }
+    }
+}
+
+/// How often samples were sent while another sample sharing their row or
+/// column was in flight, that is sent and not yet visible in the weights.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct ConflictStats {
+    /// Number of samples sent
+    pub samples: usize,
+    /// Number of samples sent with at least one conflicting sample in flight
+    pub conflicting: usize,
+    /// `conflicting` over `samples`
+    pub rate: f64,
+    /// In flight samples sharing the row, summed over the samples sent
+    pub row_conflicts: usize,
+    /// In flight samples sharing the column, summed over the samples sent
+    pub column_conflicts: usize,
+    /// Number of samples sent ahead of earlier ones held back for conflicts
+    pub reordered: usize,
+}
+
+impl ConflictStats {
+    /// Accumulate the counts of another schedule.
+    pub fn add(&mut self, other: &ConflictStats) {
+        self.samples += other.samples;
+        self.conflicting += other.conflicting;
+        self.row_conflicts += other.row_conflicts;
+        self.column_conflicts += other.column_conflicts;
+        self.reordered += other.reordered;
+        self.rate = self.conflicting as f64 / self.samples.max(1) as f64;
+    }
+}
+
+impl fmt::Display for ConflictStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "rate: {:.4} ({}/{}), row: {}, column: {}, reordered: {}",
+            self.rate,
+            self.conflicting,
+            self.samples,
+            self.row_conflicts,
+            self.column_conflicts,
+            self.reordered
+        )
+    }
+}
+
+/// Tracks the rows and columns of the samples in flight in the simulator.
+pub(crate) struct ConflictTracker {
+    /// Row and column of every sample
+    coords: Vec<(usize, usize)>,
+    /// Number of samples in flight per row
+    rows: HashMap<usize, usize>,
+    /// Number of samples in flight per column
+    cols: HashMap<usize, usize>,
+    /// Updates that become visible at a future time, in time order
+    releases: VecDeque<(Tick, Vec<(usize, UpdatePart)>)>,
+    pub(crate) stats: ConflictStats,
+}
+
+impl ConflictTracker {
+    pub(crate) fn new(matrix: &CoordListSparseMatrix<f32>) -> Self {
+        Self {
+            coords: matrix.iter().map(|&(i, j, _)| (i, j)).collect(),
+            rows: HashMap::new(),
+            cols: HashMap::new(),
+            releases: VecDeque::new(),
+            stats: ConflictStats::default(),
+        }
+    }
+
+    /// In flight samples sharing the row and the column of `sample_id`.
+    fn conflicts(&self, sample_id: usize) -> (usize, usize) {
+        let (i, j) = self.coords[sample_id];
+        (
+            self.rows.get(&i).copied().unwrap_or(0),
+            self.cols.get(&j).copied().unwrap_or(0),
+        )
+    }
+
+    pub(crate) fn has_conflicts(&self, sample_id: usize) -> bool {
+        self.conflicts(sample_id) != (0, 0)
+    }
+
+    pub(crate) fn sent(&mut self, sample_id: usize) {
+        let (row, col) = self.conflicts(sample_id);
+        self.stats.add(&ConflictStats {
+            samples: 1,
+            conflicting: usize::from(row + col > 0),
+            row_conflicts: row,
+            column_conflicts: col,
+            ..ConflictStats::default()
+        });
+        let (i, j) = self.coords[sample_id];
+        bump(&mut self.rows, i, true);
+        bump(&mut self.cols, j, true);
+    }
+
+    /// The updates of the samples were folded and become visible at
+    /// `tick`. A row or column stops conflicting once its part is visible.
+    pub(crate) fn folded(&mut self, tick: Tick, updates: Vec<(usize, UpdatePart)>) {
+        if !updates.is_empty() {
+            self.releases.push_back((tick, updates));
+        }
+    }
+
+    /// Stop tracking the samples whose updates are visible at `tick`.
+    pub(crate) fn release(&mut self, tick: Tick) {
+        while let Some((t, _)) = self.releases.front() {
+            if tick < *t {
+                return;
+            }
+            let (_, updates) = self.releases.pop_front().unwrap();
+            for (sample_id, part) in updates {
+                let (i, j) = self.coords[sample_id];
+                if part.has_row() {
+                    bump(&mut self.rows, i, false);
+                }
+                if part.has_column() {
+                    bump(&mut self.cols, j, false);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Samples at (0, 0), (0, 1), (1, 1) and (2, 2).
+    fn tracker() -> ConflictTracker {
+        let mut matrix = CoordListSparseMatrix::new_empty();
+        (0..3).for_each(|_| matrix.add_row());
+        (0..3).for_each(|_| matrix.add_col());
+        for (i, j) in [(0, 0), (0, 1), (1, 1), (2, 2)] {
+            matrix.insert(i, j, 1.);
+        }
+        ConflictTracker::new(&matrix)
+    }
+
+    #[test]
+    fn counts_in_flight_samples_sharing_a_row_or_column() {
+        let mut tracker = tracker();
+        (0..4).for_each(|s| tracker.sent(s));
+        let stats = tracker.stats;
+        assert_eq!((stats.samples, stats.conflicting), (4, 2));
+        assert_eq!((stats.row_conflicts, stats.column_conflicts), (1, 1));
+        assert_eq!(stats.rate, 0.5);
+    }
+
+    #[test]
+    fn conflicts_end_when_visible() {
+        let mut tracker = tracker();
+        tracker.sent(0);
+        assert!(tracker.has_conflicts(1));
+        tracker.folded(5, vec![(0, UpdatePart::Both)]);
+        tracker.release(4);
+        assert!(tracker.has_conflicts(1));
+        tracker.release(5);
+        assert!(!tracker.has_conflicts(1));
+    }
+
+    #[test]
+    fn parts_release_their_own_row() {
+        let mut tracker = tracker();
+        tracker.sent(1);
+        tracker.folded(1, vec![(1, UpdatePart::Row)]);
+        tracker.release(1);
+        // Row 0 is visible, column 1 is still in flight
+        assert!(!tracker.has_conflicts(0));
+        assert!(tracker.has_conflicts(2));
+        tracker.folded(2, vec![(1, UpdatePart::Column)]);
+        tracker.release(2);
+        assert!(!tracker.has_conflicts(2));
+    }
+}
@@ -0,0 +1,217 @@
+use std::collections::{BTreeMap, HashMap};
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::simulator::SimConfig;
+
+/// When the parameter server may send the next sample, trading throughput
+/// for fresher weights.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Consistency {
+    /// Whenever a worker has room for it
+    Async,
+    /// Stale synchronous parallel, only while no sample can have more than
+    /// `staleness_bound` other samples folded between the weights it read and
+    /// its own updates. Counted in samples, so the updates a sample split over
+    /// two shards sends count once
+    Ssp,
+    /// Bulk synchronous parallel, in rounds of one batch per worker, each
+    /// waiting until the updates of the previous one are visible
+    Bsp,
+}
+
+/// Holds back samples the consistency model does not allow to be sent yet.
+pub(crate) struct ConsistencyGate {
+    consistency: Consistency,
+    staleness_bound: usize,
+    batch_size: usize,
+    /// Number of samples per `Consistency::Bsp` round
+    round: usize,
+    /// Number of samples sent
+    sent_samples: usize,
+    /// Number of updates of the samples sent
+    sent_updates: usize,
+    /// Number of samples sent to every worker in the current round
+    round_sent: Vec<usize>,
+    /// Number of updates folded
+    folded_updates: usize,
+    /// Updates not folded yet and samples fully visible when it was sent,
+    /// for every sample not fully folded
+    unfolded: HashMap<usize, (usize, usize)>,
+    /// Number of samples not fully folded per number of samples fully
+    /// visible when they were sent
+    pending: BTreeMap<usize, usize>,
+    /// Number of updates folded once each sample was fully folded, in order
+    completed_at: Vec<usize>,
+}
+
+impl ConsistencyGate {
+    pub(crate) fn new(config: &SimConfig) -> Self {
+        Self {
+            consistency: config.consistency,
+            staleness_bound: config.staleness_bound,
+            batch_size: config.batch_size,
+            round: config.n_workers * config.batch_size,
+            sent_samples: 0,
+            sent_updates: 0,
+            round_sent: vec![0; config.n_workers],
+            folded_updates: 0,
+            unfolded: HashMap::new(),
+            pending: BTreeMap::new(),
+            completed_at: vec![],
+        }
+    }
+
+    /// Number of samples whose updates are all among the first `visible`
+    /// folded.
+    fn fully_visible(&self, visible: usize) -> usize {
+        self.completed_at.partition_point(|&n| n <= visible)
+    }
+
+    fn starts_round(&self) -> bool {
+        self.sent_samples.is_multiple_of(self.round)
+    }
+
+    /// Whether the next sample can be sent while `visible` updates are
+    /// visible in the weights.
+    pub(crate) fn allows(&self, visible: usize) -> bool {
+        match self.consistency {
+            Consistency::Async => true,
+            // Every sample not fully visible to the oldest one still pending,
+            // including the next one, can be folded before it
+            Consistency::Ssp => {
+                let visible = self.fully_visible(visible);
+                let oldest = self
+                    .pending
+                    .keys()
+                    .next()
+                    .map_or(visible, |&v| v.min(visible));
+                self.sent_samples <= oldest + self.staleness_bound
+            }
+            Consistency::Bsp => !self.starts_round() || visible == self.sent_updates,
+        }
+    }
+
+    /// Whether `worker` can take the next sample, which with
+    /// `Consistency::Bsp` it cannot once it has its batch of the round.
+    pub(crate) fn accepts(&self, worker: usize) -> bool {
+        self.consistency != Consistency::Bsp
+            || self.starts_round()
+            || self.round_sent[worker] < self.batch_size
+    }
+
+    /// `sample_id`, with `n_updates` updates, was sent to `worker` while
+    /// `visible` updates were visible.
+    pub(crate) fn sent(
+        &mut self,
+        sample_id: usize,
+        worker: usize,
+        n_updates: usize,
+        visible: usize,
+    ) {
+        if self.starts_round() {
+            self.round_sent.fill(0);
+        }
+        self.round_sent[worker] += 1;
+        let visible = self.fully_visible(visible);
+        self.sent_samples += 1;
+        self.sent_updates += n_updates;
+        self.unfolded.insert(sample_id, (n_updates, visible));
+        *self.pending.entry(visible).or_default() += 1;
+    }
+
+    /// An update of `sample_id` was folded, after all the updates folded
+    /// before.
+    pub(crate) fn folded(&mut self, sample_id: usize) {
+        self.folded_updates += 1;
+        let (left, visible) = self.unfolded.get_mut(&sample_id).unwrap();
+        *left -= 1;
+        if *left > 0 {
+            return;
+        }
+        let visible = *visible;
+        self.unfolded.remove(&sample_id);
+        let count = self.pending.get_mut(&visible).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            self.pending.remove(&visible);
+        }
+        self.completed_at.push(self.folded_updates);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gate(consistency: Consistency, n_workers: usize, batch_size: usize) -> ConsistencyGate {
+        ConsistencyGate::new(&SimConfig {
+            consistency,
+            staleness_bound: 0,
+            n_workers,
+            batch_size,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn ssp_counts_split_samples_once() {
+        let mut gate = gate(Consistency::Ssp, 2, 1);
+        assert!(gate.allows(0));
+        // Sample 7 is split over two shards
+        gate.sent(7, 0, 2, 0);
+        assert!(!gate.allows(0));
+        gate.folded(7);
+        assert!(!gate.allows(1));
+        gate.folded(7);
+        assert!(!gate.allows(1));
+        assert!(gate.allows(2));
+        gate.sent(3, 1, 1, 2);
+        assert!(!gate.allows(2));
+        gate.folded(3);
+        assert!(gate.allows(3));
+    }
+
+    #[test]
+    fn ssp_bound_follows_the_oldest_pending_sample() {
+        let mut gate = gate(Consistency::Ssp, 2, 1);
+        gate.staleness_bound = 2;
+        gate.sent(0, 0, 2, 0);
+        gate.sent(1, 1, 1, 0);
+        assert!(gate.allows(0));
+        gate.sent(2, 0, 1, 0);
+        assert!(!gate.allows(0));
+        // Sample 1 is folded first, sample 0 still read none of them
+        gate.folded(1);
+        assert!(!gate.allows(1));
+        gate.folded(0);
+        gate.folded(0);
+        // Sample 2 read none of them either
+        assert!(!gate.allows(3));
+        gate.folded(2);
+        assert!(gate.allows(4));
+    }
+
+    #[test]
+    fn bsp_sends_one_batch_per_worker_per_round() {
+        let mut gate = gate(Consistency::Bsp, 2, 2);
+        assert!(gate.allows(0) && gate.accepts(0) && gate.accepts(1));
+        gate.sent(0, 0, 1, 0);
+        gate.sent(1, 0, 1, 0);
+        assert!(gate.allows(0));
+        assert!(!gate.accepts(0) && gate.accepts(1));
+        gate.sent(2, 1, 1, 0);
+        gate.sent(3, 1, 2, 0);
+        // The round is sent, the next waits until all of it is visible
+        assert!(!gate.allows(0));
+        (0..4).for_each(|s| gate.folded(s));
+        gate.folded(3);
+        assert!(!gate.allows(4));
+        assert!(gate.allows(5));
+        assert!(gate.accepts(0) && gate.accepts(1));
+        gate.sent(4, 1, 1, 5);
+        assert!(gate.accepts(0) && gate.accepts(1));
+    }
+}
@@ -1 +1,2 @@
 pub mod netflix;
+pub mod split;
@@ -7,9 +7,30 @@ use std::{
 
 use crate::data_structures::CoordListSparseMatrix;
 
-type NetflixMatrix = CoordListSparseMatrix<f32>;
+pub type NetflixMatrix = CoordListSparseMatrix<f32>;
 
-fn get_data_dir() -> PathBuf {
+/// Size of one normalized rating unit in stars, see `load_one_movie`.
+pub const RATING_SCALE: f32 = 2.5;
+
+/// Which part of the Netflix prize data to load.
+#[derive(Clone, Debug)]
+pub struct NetflixConfig {
+    /// Number of movies to load
+    pub n_movies: usize,
+    /// Directory holding the per-movie `mv_*.txt` files
+    pub data_dir: PathBuf,
+}
+
+impl Default for NetflixConfig {
+    fn default() -> Self {
+        Self {
+            n_movies: 100,
+            data_dir: get_data_dir(),
+        }
+    }
+}
+
+pub fn get_data_dir() -> PathBuf {
     let mut base_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
     base_dir.push("..");
     base_dir.push("..");
@@ -19,9 +40,17 @@ fn get_data_dir() -> PathBuf {
     base_dir
 }
 
+/// Parse a `YYYY-MM-DD` date into `YYYYMMDD`, which sorts chronologically.
+fn parse_date(date: &str) -> u32 {
+    date.split('-')
+        .map(|tok| tok.parse::<u32>().unwrap())
+        .fold(0, |acc, n| acc * 100 + n)
+}
+
 fn load_one_movie(
     dir_entry: DirEntry,
     m: &mut NetflixMatrix,
+    dates: &mut Vec<u32>,
     user_to_row: &mut HashMap<usize, usize>,
 ) {
     let path = dir_entry.path();
@@ -38,7 +67,8 @@ fn load_one_movie(
 
         let user_id: usize = toks.next().unwrap().parse().unwrap();
         let rating: f32 = toks.next().unwrap().parse().unwrap();
-        let rating_norm: f32 = (rating / 2.5) - 1.;
+        let rating_norm: f32 = (rating / RATING_SCALE) - 1.;
+        let date = parse_date(toks.next().unwrap());
 
         let row: usize = match user_to_row.entry(user_id) {
             Entry::Occupied(o) => *o.get(),
@@ -50,18 +80,25 @@ fn load_one_movie(
         };
 
         m.insert(row, col, rating_norm);
+        dates.push(date);
     }
 }
 
-pub fn load_netflix_dataset(n_movies: usize) -> NetflixMatrix {
+pub fn load_netflix_dataset(config: &NetflixConfig) -> NetflixMatrix {
+    load_netflix_dataset_with_dates(config).0
+}
+
+/// Load the ratings along with the `YYYYMMDD` date of every entry, in the
+/// same order as the entries of the matrix.
+pub fn load_netflix_dataset_with_dates(config: &NetflixConfig) -> (NetflixMatrix, Vec<u32>) {
     let mut matrix = NetflixMatrix::new_empty();
+    let mut dates = vec![];
     let mut user_to_row: HashMap<usize, usize> = HashMap::new();
-    let paths = read_dir(get_data_dir()).unwrap();
+    let paths = read_dir(&config.data_dir).unwrap();
 
     paths
-        .take(n_movies)
-        .for_each(|de| load_one_movie(de.unwrap(), &mut matrix, &mut user_to_row));
+        .take(config.n_movies)
+        .for_each(|de| load_one_movie(de.unwrap(), &mut matrix, &mut dates, &mut user_to_row));
 
-    matrix
+    (matrix, dates)
 }
-
@@ -0,0 +1,210 @@
+use std::{collections::HashMap, fmt::Display};
+
+use clap::ValueEnum;
+use ndarray_rand::rand::seq::SliceRandom;
+use serde::Serialize;
+
+use crate::{
+    data_structures::CoordListSparseMatrix,
+    seed::{stream_rng, Stream},
+};
+
+/// How to hold out entries for validation and testing.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SplitStrategy {
+    /// Train on every entry, no held out sets
+    None,
+    /// Hold out uniformly random entries
+    Random,
+    /// Hold out the same fraction of every user's (row's) entries
+    PerUser,
+    /// Hold out the most recent entries
+    ByTime,
+}
+
+#[derive(Clone, Debug)]
+pub struct SplitConfig {
+    pub strategy: SplitStrategy,
+    /// Fraction of the entries to use for validation
+    pub validation_fraction: f32,
+    /// Fraction of the entries to use for testing
+    pub test_fraction: f32,
+    /// RNG seed for the random strategies
+    pub seed: u64,
+}
+
+impl Default for SplitConfig {
+    fn default() -> Self {
+        Self {
+            strategy: SplitStrategy::None,
+            validation_fraction: 0.1,
+            test_fraction: 0.1,
+            seed: 4102000,
+        }
+    }
+}
+
+/// Disjoint subsets of a matrix. All three keep the shape of the original
+/// so that row and column indices mean the same thing in each.
+pub struct DataSplit<Elem: Copy + Display> {
+    pub train: CoordListSparseMatrix<Elem>,
+    pub validation: CoordListSparseMatrix<Elem>,
+    pub test: CoordListSparseMatrix<Elem>,
+}
+
+/// Split the entries of `matrix`. `timestamps` holds a sortable time for
+/// every entry and is required by `SplitStrategy::ByTime`.
+pub fn split<Elem: Copy + Display>(
+    matrix: &CoordListSparseMatrix<Elem>,
+    timestamps: Option<&[u32]>,
+    config: &SplitConfig,
+) -> DataSplit<Elem> {
+    let mut rng = stream_rng(config.seed, 0, Stream::Split);
+    let n_held_out = |n: usize, fraction: f32| (n as f32 * fraction).round() as usize;
+
+    // Entries ordered so that the held out ones come last, test after validation
+    let groups: Vec<Vec<usize>> = match config.strategy {
+        SplitStrategy::None => vec![(0..matrix.nnz()).collect()],
+        SplitStrategy::Random => {
+            let mut idx: Vec<usize> = (0..matrix.nnz()).collect();
+            idx.shuffle(&mut rng);
+            vec![idx]
+        }
+        SplitStrategy::PerUser => {
+            let mut rows: HashMap<usize, Vec<usize>> = HashMap::new();
+            for (i, &(row, _, _)) in matrix.iter().enumerate() {
+                rows.entry(row).or_default().push(i);
+            }
+            let mut rows: Vec<_> = rows.into_values().collect();
+            rows.sort_unstable();
+            for idx in rows.iter_mut() {
+                idx.shuffle(&mut rng);
+            }
+            rows
+        }
+        SplitStrategy::ByTime => {
+            let timestamps = timestamps.expect("splitting by time requires timestamps");
+            assert_eq!(timestamps.len(), matrix.nnz());
+            let mut idx: Vec<usize> = (0..matrix.nnz()).collect();
+            idx.sort_by_key(|&i| timestamps[i]);
+            vec![idx]
+        }
+    };
+
+    let mut res = DataSplit {
+        train: CoordListSparseMatrix::new_with_shape_of(matrix),
+        validation: CoordListSparseMatrix::new_with_shape_of(matrix),
+        test: CoordListSparseMatrix::new_with_shape_of(matrix),
+    };
+    let mut in_set = vec![0; matrix.nnz()];
+    for idx in &groups {
+        let (n_val, n_test) = match config.strategy {
+            SplitStrategy::None => (0, 0),
+            _ => (
+                n_held_out(idx.len(), config.validation_fraction),
+                n_held_out(idx.len(), config.test_fraction),
+            ),
+        };
+        let n_train = idx.len().saturating_sub(n_val + n_test);
+        for (pos, &i) in idx.iter().enumerate() {
+            in_set[i] = match pos {
+                p if p < n_train => 0,
+                p if p < n_train + n_val => 1,
+                _ => 2,
+            };
+        }
+    }
+
+    // Insert in the original order so that the loader's layout is preserved
+    for (&(row, col, elem), set) in matrix.iter().zip(in_set) {
+        let dst = match set {
+            0 => &mut res.train,
+            1 => &mut res.validation,
+            _ => &mut res.test,
+        };
+        dst.insert(row, col, elem);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 10 users with 10 entries each, every entry holding its index.
+    fn matrix() -> CoordListSparseMatrix<f32> {
+        let mut matrix = CoordListSparseMatrix::new_empty();
+        (0..10).for_each(|_| matrix.add_row());
+        (0..10).for_each(|_| matrix.add_col());
+        for i in 0..100 {
+            matrix.insert(i / 10, i % 10, i as f32);
+        }
+        matrix
+    }
+
+    fn entries(matrix: &CoordListSparseMatrix<f32>) -> Vec<usize> {
+        matrix.iter().map(|&(_, _, e)| e as usize).collect()
+    }
+
+    fn split_with(strategy: SplitStrategy, timestamps: Option<&[u32]>) -> DataSplit<f32> {
+        let config = SplitConfig {
+            strategy,
+            ..Default::default()
+        };
+        split(&matrix(), timestamps, &config)
+    }
+
+    #[test]
+    fn sets_partition_the_entries() {
+        for strategy in [SplitStrategy::Random, SplitStrategy::PerUser] {
+            let res = split_with(strategy, None);
+            assert_eq!(
+                (res.train.nnz(), res.validation.nnz(), res.test.nnz()),
+                (80, 10, 10)
+            );
+            for set in [&res.train, &res.validation, &res.test] {
+                assert_eq!((set.n_rows(), set.n_cols()), (10, 10));
+            }
+            let mut all: Vec<usize> = [res.train, res.validation, res.test]
+                .iter()
+                .flat_map(entries)
+                .collect();
+            all.sort_unstable();
+            assert_eq!(all, (0..100).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn no_split_keeps_everything_in_order() {
+        let res = split_with(SplitStrategy::None, None);
+        assert_eq!(entries(&res.train), (0..100).collect::<Vec<_>>());
+        assert_eq!(res.validation.nnz() + res.test.nnz(), 0);
+    }
+
+    #[test]
+    fn per_user_holds_out_from_every_user() {
+        let res = split_with(SplitStrategy::PerUser, None);
+        for row in 0..10 {
+            assert_eq!(res.train.nnz_row(row), 8);
+            assert_eq!(res.validation.nnz_row(row), 1);
+            assert_eq!(res.test.nnz_row(row), 1);
+        }
+    }
+
+    #[test]
+    fn by_time_holds_out_the_latest() {
+        // Entry i happened at time 100 - i
+        let timestamps: Vec<u32> = (0..100).map(|i| 100 - i).collect();
+        let res = split_with(SplitStrategy::ByTime, Some(&timestamps));
+        assert_eq!(entries(&res.validation), (10..20).collect::<Vec<_>>());
+        assert_eq!(entries(&res.test), (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn same_seed_same_split() {
+        let a = split_with(SplitStrategy::Random, None);
+        let b = split_with(SplitStrategy::Random, None);
+        assert_eq!(entries(&a.test), entries(&b.test));
+    }
+}
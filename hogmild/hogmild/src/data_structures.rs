@@ -23,6 +23,15 @@ impl<Elem: Copy + Display> CoordListSparseMatrix<Elem> {
         }
     }
 
+    /// An empty matrix with the same shape as `other`.
+    pub fn new_with_shape_of(other: &Self) -> Self {
+        Self {
+            n_rows: other.n_rows,
+            n_cols: other.n_cols,
+            ..Self::new_empty()
+        }
+    }
+
     pub fn add_row(&mut self) {
         self.n_rows += 1;
     }
@@ -57,7 +66,7 @@ impl<Elem: Copy + Display> CoordListSparseMatrix<Elem> {
         self.nnz_cols.get(&col).map_or(0, |&val| val)
     }
 
-    pub fn iter(&self) -> std::slice::Iter<(usize, usize, Elem)> {
+    pub fn iter(&self) -> std::slice::Iter<'_, (usize, usize, Elem)> {
         self.data.iter()
     }
 }
@@ -0,0 +1,189 @@
+use std::{fs, io, path::Path};
+
+use clap::ValueEnum;
+use ndarray_rand::{
+    rand::{distributions::WeightedIndex, rngs::StdRng, Rng},
+    rand_distr::{Distribution, Exp},
+};
+use serde::Serialize;
+
+use crate::simulator::Tick;
+
+/// Shape of the time a sample/update spends on a network link.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DelayDistribution {
+    /// Always the mean
+    Constant,
+    /// Uniform within the mean plus or minus the spread
+    Uniform,
+    /// Exponential with the given mean
+    Exponential,
+    /// Drawn from a histogram of measured delays
+    Empirical,
+}
+
+/// Delay model of one direction of the network.
+#[derive(Clone, Debug)]
+pub struct DelayModel {
+    pub distribution: DelayDistribution,
+    /// Half width of the `DelayDistribution::Uniform` range
+    pub spread: Tick,
+    /// `(delay, weight)` pairs for `DelayDistribution::Empirical`
+    pub histogram: Vec<(Tick, f64)>,
+}
+
+impl Default for DelayModel {
+    fn default() -> Self {
+        Self {
+            distribution: DelayDistribution::Constant,
+            spread: 0,
+            histogram: vec![],
+        }
+    }
+}
+
+/// Read a histogram with one `delay weight` pair per line, separated by a
+/// comma or whitespace. Empty lines and lines starting with `#` are skipped.
+/// The weights must be non-negative and not all zero.
+pub fn read_histogram(path: &Path) -> io::Result<Vec<(Tick, f64)>> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let histogram = fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let mut fields = line
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|f| !f.is_empty());
+            let delay = fields.next().and_then(|f| f.parse().ok());
+            let weight = fields.next().map_or(Some(1.), |f| f.parse().ok());
+            match (delay, weight) {
+                (Some(delay), Some(weight)) => Ok((delay, weight)),
+                _ => Err(invalid(format!("bad histogram line: {line}"))),
+            }
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    WeightedIndex::new(histogram.iter().map(|&(_, w)| w))
+        .map_err(|e| invalid(format!("bad histogram weights: {e}")))?;
+    Ok(histogram)
+}
+
+/// Draws the delays of one link.
+pub(crate) struct DelaySampler {
+    distribution: DelayDistribution,
+    mean: Tick,
+    spread: Tick,
+    delays: Vec<Tick>,
+    weights: Option<WeightedIndex<f64>>,
+}
+
+impl DelaySampler {
+    pub(crate) fn new(model: &DelayModel, mean: Tick) -> Self {
+        let weights = (model.distribution == DelayDistribution::Empirical).then(|| {
+            WeightedIndex::new(model.histogram.iter().map(|&(_, w)| w))
+                .expect("empirical delay histogram needs positive weights")
+        });
+        Self {
+            distribution: model.distribution,
+            mean,
+            spread: model.spread.min(mean),
+            delays: model.histogram.iter().map(|&(d, _)| d).collect(),
+            weights,
+        }
+    }
+
+    /// The delay of the next sample/update. Draws nothing for a constant
+    /// delay, so it does not disturb the other uses of the stream.
+    pub(crate) fn sample(&self, rng: &mut StdRng) -> Tick {
+        match self.distribution {
+            DelayDistribution::Constant => self.mean,
+            DelayDistribution::Uniform => {
+                rng.gen_range(self.mean - self.spread..=self.mean + self.spread)
+            }
+            DelayDistribution::Exponential if self.mean == 0 => 0,
+            DelayDistribution::Exponential => {
+                let exp = Exp::new(1. / self.mean as f64).unwrap();
+                exp.sample(rng).round() as Tick
+            }
+            DelayDistribution::Empirical => self.delays[self.weights.as_ref().unwrap().sample(rng)],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray_rand::rand::SeedableRng;
+
+    fn sampler(distribution: DelayDistribution, spread: Tick, mean: Tick) -> DelaySampler {
+        let model = DelayModel {
+            distribution,
+            spread,
+            histogram: vec![(3, 1.), (5, 0.), (9, 3.)],
+        };
+        DelaySampler::new(&model, mean)
+    }
+
+    fn draws(sampler: &DelaySampler, n: usize) -> Vec<Tick> {
+        let mut rng = StdRng::seed_from_u64(0);
+        (0..n).map(|_| sampler.sample(&mut rng)).collect()
+    }
+
+    #[test]
+    fn histogram_files() {
+        let path = std::env::temp_dir().join(format!("hogmild-delay-{}", std::process::id()));
+        fs::write(&path, "# delay weight\n3 1\n\n9, 2.5\n12\n").unwrap();
+        assert_eq!(
+            read_histogram(&path).unwrap(),
+            [(3, 1.), (9, 2.5), (12, 1.)]
+        );
+        fs::write(&path, "3 1\n-4 1\n").unwrap();
+        let err = read_histogram(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            read_histogram(&path).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn constant_draws_nothing() {
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(
+            sampler(DelayDistribution::Constant, 2, 6).sample(&mut rng),
+            6
+        );
+        assert_eq!(rng.gen::<u64>(), StdRng::seed_from_u64(0).gen::<u64>());
+    }
+
+    #[test]
+    fn uniform_within_spread() {
+        let delays = draws(&sampler(DelayDistribution::Uniform, 2, 6), 1000);
+        assert!(delays.iter().all(|d| (4..=8).contains(d)));
+        assert!(delays.contains(&4) && delays.contains(&8));
+        // The spread is capped by the mean so delays stay non-negative
+        let delays = draws(&sampler(DelayDistribution::Uniform, 10, 3), 1000);
+        assert!(delays.iter().all(|&d| d <= 6));
+    }
+
+    #[test]
+    fn exponential_mean() {
+        let delays = draws(&sampler(DelayDistribution::Exponential, 0, 20), 10000);
+        let mean = delays.iter().sum::<Tick>() as f64 / delays.len() as f64;
+        assert!((mean - 20.).abs() < 1., "{mean}");
+        assert_eq!(
+            draws(&sampler(DelayDistribution::Exponential, 0, 0), 10),
+            [0; 10]
+        );
+    }
+
+    #[test]
+    fn empirical_follows_the_weights() {
+        let delays = draws(&sampler(DelayDistribution::Empirical, 0, 6), 10000);
+        assert!(!delays.contains(&5));
+        let threes = delays.iter().filter(|&&d| d == 3).count() as f64;
+        assert!((threes / delays.len() as f64 - 0.25).abs() < 0.02);
+    }
+}
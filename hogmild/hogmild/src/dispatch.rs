@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+
+use clap::ValueEnum;
+use ndarray_rand::rand::{rngs::StdRng, seq::SliceRandom};
+use serde::Serialize;
+
+use crate::{
+    conflicts::bump,
+    data_structures::CoordListSparseMatrix,
+    seed::{stream_rng, Stream},
+    simulator::SimConfig,
+};
+
+/// How the parameter server picks the worker each sample is sent to.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DispatchStrategy {
+    /// Fill the workers with room in index order
+    InOrder,
+    /// Start with the worker after the last one served
+    RoundRobin,
+    /// The worker with the fewest samples in flight
+    LeastLoaded,
+    /// A uniformly random worker
+    Random,
+    /// The worker whose in flight samples share the fewest rows/columns with
+    /// the sample, then the least loaded one. Needs the matrix coordinates
+    ConflictAware,
+}
+
+/// Picks the worker every sample is sent to. A worker is in flight with a
+/// sample from the time it is sent until its update is received.
+pub trait DispatchPolicy {
+    /// Pick the worker to send `sample_id` to among `free`, the ones whose
+    /// sample FIFO has room, in index order and never empty. `in_flight[w]`
+    /// is the number of samples in flight at worker `w`.
+    fn choose(&mut self, sample_id: usize, free: &[usize], in_flight: &[usize]) -> usize;
+
+    /// `sample_id` was sent to `worker`.
+    fn sent(&mut self, _worker: usize, _sample_id: usize) {}
+
+    /// The update of `sample_id` from `worker` was received.
+    fn received(&mut self, _worker: usize, _sample_id: usize) {}
+}
+
+/// The policy `config.dispatch` names. `coords` are the matrix the sample
+/// ids index into, only needed by `DispatchStrategy::ConflictAware`.
+pub fn new_policy(
+    config: &SimConfig,
+    coords: Option<&CoordListSparseMatrix<f32>>,
+) -> Box<dyn DispatchPolicy> {
+    match config.dispatch {
+        DispatchStrategy::InOrder => Box::new(InOrder),
+        DispatchStrategy::RoundRobin => Box::new(RoundRobin { next: 0 }),
+        DispatchStrategy::LeastLoaded => Box::new(LeastLoaded),
+        DispatchStrategy::Random => Box::new(Random {
+            rng: stream_rng(config.seed, config.epoch, Stream::Dispatch),
+        }),
+        DispatchStrategy::ConflictAware => {
+            let matrix = coords.expect("conflict-aware dispatch needs the matrix coordinates");
+            Box::new(ConflictAware::new(matrix, config.n_workers))
+        }
+    }
+}
+
+pub struct InOrder;
+
+impl DispatchPolicy for InOrder {
+    fn choose(&mut self, _sample_id: usize, free: &[usize], _in_flight: &[usize]) -> usize {
+        free[0]
+    }
+}
+
+pub struct RoundRobin {
+    /// The worker to try first
+    next: usize,
+}
+
+impl DispatchPolicy for RoundRobin {
+    fn choose(&mut self, _sample_id: usize, free: &[usize], _in_flight: &[usize]) -> usize {
+        let worker = free
+            .iter()
+            .copied()
+            .find(|&w| w >= self.next)
+            .unwrap_or(free[0]);
+        self.next = worker + 1;
+        worker
+    }
+}
+
+pub struct LeastLoaded;
+
+impl DispatchPolicy for LeastLoaded {
+    fn choose(&mut self, _sample_id: usize, free: &[usize], in_flight: &[usize]) -> usize {
+        *free.iter().min_by_key(|&&w| in_flight[w]).unwrap()
+    }
+}
+
+pub struct Random {
+    rng: StdRng,
+}
+
+impl DispatchPolicy for Random {
+    fn choose(&mut self, _sample_id: usize, free: &[usize], _in_flight: &[usize]) -> usize {
+        *free.choose(&mut self.rng).unwrap()
+    }
+}
+
+pub struct ConflictAware {
+    /// Row and column of every sample
+    coords: Vec<(usize, usize)>,
+    /// Number of samples in flight at every worker per row
+    rows: Vec<HashMap<usize, usize>>,
+    /// Number of samples in flight at every worker per column
+    cols: Vec<HashMap<usize, usize>>,
+}
+
+impl ConflictAware {
+    pub fn new(matrix: &CoordListSparseMatrix<f32>, n_workers: usize) -> Self {
+        Self {
+            coords: matrix.iter().map(|&(i, j, _)| (i, j)).collect(),
+            rows: vec![HashMap::new(); n_workers],
+            cols: vec![HashMap::new(); n_workers],
+        }
+    }
+
+    /// Number of samples in flight at `worker` sharing a row or a column
+    /// with `sample_id`.
+    fn conflicts(&self, worker: usize, sample_id: usize) -> usize {
+        let (i, j) = self.coords[sample_id];
+        self.rows[worker].get(&i).copied().unwrap_or(0)
+            + self.cols[worker].get(&j).copied().unwrap_or(0)
+    }
+}
+
+impl DispatchPolicy for ConflictAware {
+    fn choose(&mut self, sample_id: usize, free: &[usize], in_flight: &[usize]) -> usize {
+        *free
+            .iter()
+            .min_by_key(|&&w| (self.conflicts(w, sample_id), in_flight[w]))
+            .unwrap()
+    }
+
+    fn sent(&mut self, worker: usize, sample_id: usize) {
+        let (i, j) = self.coords[sample_id];
+        bump(&mut self.rows[worker], i, true);
+        bump(&mut self.cols[worker], j, true);
+    }
+
+    fn received(&mut self, worker: usize, sample_id: usize) {
+        let (i, j) = self.coords[sample_id];
+        bump(&mut self.rows[worker], i, false);
+        bump(&mut self.cols[worker], j, false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Worker each of `n` samples is sent to when `free` workers all have
+    /// room and none has a sample in flight.
+    fn dispatch(policy: &mut dyn DispatchPolicy, free: &[usize], n: usize) -> Vec<usize> {
+        let in_flight = vec![0; 4];
+        (0..n).map(|s| policy.choose(s, free, &in_flight)).collect()
+    }
+
+    #[test]
+    fn in_order_fills_the_first_worker() {
+        assert_eq!(dispatch(&mut InOrder, &[0, 1, 2, 3], 3), [0, 0, 0]);
+        assert_eq!(dispatch(&mut InOrder, &[2, 3], 2), [2, 2]);
+    }
+
+    #[test]
+    fn round_robin_spreads_over_all_workers() {
+        let mut policy = RoundRobin { next: 0 };
+        assert_eq!(dispatch(&mut policy, &[0, 1, 2, 3], 6), [0, 1, 2, 3, 0, 1]);
+        // Workers without room are skipped, wrapping around to the first
+        assert_eq!(dispatch(&mut policy, &[0, 3], 3), [3, 0, 3]);
+    }
+
+    #[test]
+    fn least_loaded_picks_the_fewest_in_flight() {
+        let mut policy = LeastLoaded;
+        let in_flight = [3, 1, 0, 2];
+        assert_eq!(policy.choose(0, &[0, 1, 2, 3], &in_flight), 2);
+        assert_eq!(policy.choose(0, &[0, 1, 3], &in_flight), 1);
+        // Ties go to the lowest index
+        assert_eq!(policy.choose(0, &[0, 1, 3], &[1, 1, 0, 1]), 0);
+    }
+
+    #[test]
+    fn random_reaches_every_free_worker() {
+        let mut policy = Random {
+            rng: stream_rng(0, 0, Stream::Dispatch),
+        };
+        let mut chosen = dispatch(&mut policy, &[1, 2, 3], 100);
+        chosen.sort();
+        chosen.dedup();
+        assert_eq!(chosen, [1, 2, 3]);
+    }
+
+    #[test]
+    fn conflict_aware_avoids_workers_holding_the_row_or_column() {
+        // Samples at (0, 0), (0, 1), (1, 0), (0, 2) and (2, 2)
+        let mut matrix = CoordListSparseMatrix::new_empty();
+        (0..3).for_each(|_| matrix.add_row());
+        (0..3).for_each(|_| matrix.add_col());
+        for (i, j) in [(0, 0), (0, 1), (1, 0), (0, 2), (2, 2)] {
+            matrix.insert(i, j, 1.);
+        }
+        let mut policy = ConflictAware::new(&matrix, 3);
+        let free = [0, 1, 2];
+
+        policy.sent(0, 0);
+        // Sample 1 shares the row, sample 2 the column of worker 0's sample
+        assert_eq!(policy.choose(1, &free, &[1, 0, 0]), 1);
+        assert_eq!(policy.choose(2, &free, &[1, 0, 0]), 1);
+        policy.sent(1, 1);
+        // Sample 3 shares the row of both, the least loaded of them wins
+        // unless a worker without the conflict is free
+        assert_eq!(policy.choose(3, &[0, 1], &[2, 1, 0]), 1);
+        assert_eq!(policy.choose(3, &free, &[2, 1, 3]), 2);
+        // Without conflicts the least loaded worker wins
+        assert_eq!(policy.choose(4, &free, &[1, 0, 2]), 1);
+
+        policy.received(0, 0);
+        assert_eq!(policy.choose(1, &[0, 1], &[0, 1, 0]), 0);
+    }
+}
@@ -0,0 +1,21 @@
+//! Simulator for asynchronous SGD hardware and the models trained on its
+//! update schedules.
+
+pub mod args;
+pub mod banks;
+pub mod compression;
+pub mod conflicts;
+pub mod consistency;
+pub mod data_loader;
+pub mod data_structures;
+pub mod delay;
+pub mod dispatch;
+pub mod mat_comp;
+pub mod precision;
+pub mod report;
+pub mod seed;
+pub mod shuffle;
+pub mod simulator;
+pub mod staleness;
+pub mod trace;
+pub mod utilization;
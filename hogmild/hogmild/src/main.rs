@@ -1,35 +1,205 @@
-use clap::Parser;
+use std::{fmt::Display, time::Instant};
 
-mod args;
-mod data_loader;
-mod data_structures;
-mod mat_comp;
-mod simulator;
+use hogmild::{
+    args::{Args, OutputFormat},
+    banks::BankMapping,
+    compression::CompressionConfig,
+    data_loader::{
+        netflix::{self, load_netflix_dataset_with_dates, NetflixConfig},
+        split::{split, DataSplit, SplitConfig, SplitStrategy},
+    },
+    data_structures::CoordListSparseMatrix,
+    dispatch::new_policy,
+    mat_comp::{MatrixCompletion, MatrixCompletionConfig, Metrics, TrainHistory},
+    precision::{Bf16, Fixed, Fp16, NumberFormat, Numeric, PrecisionConfig},
+    report::RunReport,
+    shuffle::{sample_order, ShuffleConfig},
+    simulator::{
+        run_simulation_with_policy, run_simulation_with_trace, Sample, SimConfig, Tick, UpdateLogs,
+    },
+    staleness::StalenessStats,
+    utilization::UtilizationReport,
+};
 
-use args::Args;
-use simulator::run_simulation;
+/// Simulate the given order of the entries of `matrix`, writing a trace of it
+/// and reporting resource usage if asked to.
+fn simulate(
+    args: &Args,
+    config: &SimConfig,
+    order: Vec<usize>,
+    matrix: Option<&CoordListSparseMatrix<f32>>,
+    report: &mut RunReport,
+) -> (Tick, UpdateLogs) {
+    let policy = new_policy(config, matrix);
+    if args.trace.is_none() && !args.utilization {
+        return run_simulation_with_policy(config, order, policy, matrix);
+    }
+
+    let (cycle_count, updates, trace) = run_simulation_with_trace(config, order, policy, matrix);
+    if let Some(path) = &args.trace {
+        trace
+            .write_chrome_json(path)
+            .unwrap_or_else(|e| panic!("Failed to write trace {}: {}", path.display(), e));
+    }
+    if args.utilization {
+        report.utilization = Some(UtilizationReport::from_trace(&trace, config, cycle_count));
+    }
+    (cycle_count, updates)
+}
+
+/// Train matrix completion with the weights stored as `W` and the gradients
+/// as `G`, replaying `updates` unless training with Hogwild! or simulating
+/// again. Returns the history and the error on the test set, if held out.
+fn train<W: Numeric, G: Numeric>(
+    args: &Args,
+    sim_config: &SimConfig,
+    data: DataSplit<f32>,
+    updates: Vec<Sample>,
+) -> (TrainHistory, Option<Metrics>) {
+    let has_held_out = args.split != SplitStrategy::None;
+    let model_config = MatrixCompletionConfig::from(args);
+    let precision = PrecisionConfig::from(args);
+    let mut matrix_completion =
+        MatrixCompletion::<W, G>::with_precision(model_config, precision, data.train, updates);
+    matrix_completion.shuffle = ShuffleConfig::from(args);
+    matrix_completion.compression = CompressionConfig::from(args);
+    if args.resimulate {
+        matrix_completion.resimulate = Some(sim_config.clone());
+    }
+    matrix_completion.verbose = args.output_format == OutputFormat::Text;
+    matrix_completion.rating_scale = netflix::RATING_SCALE;
+    if has_held_out {
+        matrix_completion.validation = Some(data.validation);
+    }
+    let history = if args.hogwild {
+        matrix_completion.train_hogwild(args.n_workers)
+    } else {
+        matrix_completion.train()
+    };
+    let test = has_held_out.then(|| matrix_completion.evaluate(&data.test));
+    (history, test)
+}
+
+/// `train` with the weights stored as `W` and the gradients in
+/// `--gradient-format`.
+fn train_weights_as<W: Numeric>(
+    args: &Args,
+    sim_config: &SimConfig,
+    data: DataSplit<f32>,
+    updates: Vec<Sample>,
+) -> (TrainHistory, Option<Metrics>) {
+    match args.gradient_format {
+        NumberFormat::F32 => train::<W, f32>(args, sim_config, data, updates),
+        NumberFormat::Bf16 => train::<W, Bf16>(args, sim_config, data, updates),
+        NumberFormat::Fp16 => train::<W, Fp16>(args, sim_config, data, updates),
+        NumberFormat::Fixed => train::<W, Fixed>(args, sim_config, data, updates),
+    }
+}
 
 fn main() {
-    let args = Args::parse();
+    let args = Args::load();
+    let sim_config = SimConfig::from(&args);
+    let text = args.output_format == OutputFormat::Text;
+    let mapped_banks = text && args.bank_mapping != BankMapping::Pool;
+    let print_data = |data: &dyn Display| match (args.print_data, text) {
+        (false, _) => {}
+        (true, true) => print!("{}", data),
+        (true, false) => eprint!("{}", data),
+    };
+
+    let start = Instant::now();
+    let mut report = RunReport::new(&args);
 
     if args.simulation {
-        let (cycle_count, _) = run_simulation(&args, args.num_samples);
-        println!("{}", cycle_count);
-        return;
-    }
-
-    match args.dataset.as_str() {
-        "netflix" => {
-            let matrix = data_loader::netflix::load_netflix_dataset(args.n_movies);
-            let num_samples = matrix.nnz();
-            let (cycle_count, updates) = run_simulation(&args, num_samples);
-            println!("cycles per epoch: {}", cycle_count);
-            let mut matrix_completion =
-                mat_comp::MatrixCompletion::new(&args, matrix, updates.samples);
-            let _ = matrix_completion.train();
+        let order = sample_order::<f32>(&ShuffleConfig::from(&args), args.num_samples, None, 0);
+        let (cycle_count, updates) = simulate(&args, &sim_config, order, None, &mut report);
+        let staleness = StalenessStats::from_logs(&updates.samples);
+        if text {
+            println!("{}", cycle_count);
+            println!("staleness {}", staleness.summary);
         }
-        d => {
-            panic!("Unknown dataset {}", d)
+        print_data(&updates);
+        report.cycles = Some(cycle_count);
+        report.staleness = Some(staleness);
+        report.banks = Some(updates.banks);
+    } else {
+        match args.dataset.as_str() {
+            "netflix" => {
+                let (matrix, dates) = load_netflix_dataset_with_dates(&NetflixConfig::from(&args));
+                print_data(&matrix);
+                let data = split(&matrix, Some(&dates), &SplitConfig::from(&args));
+
+                let shuffle = ShuffleConfig::from(&args);
+                let first_order = || sample_order(&shuffle, data.train.nnz(), Some(&data.train), 0);
+                let updates = if args.hogwild {
+                    vec![]
+                } else if args.resimulate {
+                    // The first epoch's schedule is simulated again while training
+                    if args.trace.is_some() || args.utilization {
+                        let train = Some(&data.train);
+                        simulate(&args, &sim_config, first_order(), train, &mut report);
+                    }
+                    vec![]
+                } else {
+                    let train = Some(&data.train);
+                    let (cycle_count, updates) =
+                        simulate(&args, &sim_config, first_order(), train, &mut report);
+                    if text {
+                        println!("cycles per epoch: {}", cycle_count);
+                    }
+                    report.cycles = Some(cycle_count);
+                    report.conflicts = updates.conflicts;
+                    report.banks = Some(updates.banks);
+                    updates.samples
+                };
+
+                let (history, test) = match args.accumulation_format {
+                    NumberFormat::F32 => train_weights_as::<f32>(&args, &sim_config, data, updates),
+                    NumberFormat::Bf16 => {
+                        train_weights_as::<Bf16>(&args, &sim_config, data, updates)
+                    }
+                    NumberFormat::Fp16 => {
+                        train_weights_as::<Fp16>(&args, &sim_config, data, updates)
+                    }
+                    NumberFormat::Fixed => {
+                        train_weights_as::<Fixed>(&args, &sim_config, data, updates)
+                    }
+                };
+                report.set_history(history);
+                if let (true, Some(total_cycles)) = (text, report.total_cycles) {
+                    println!("total cycles: {}", total_cycles);
+                }
+                if let (true, Some(staleness)) = (text, &report.staleness) {
+                    println!("staleness {}", staleness.summary);
+                }
+                if let (true, Some(conflicts)) = (text, &report.conflicts) {
+                    println!("conflicts {}", conflicts);
+                }
+                if let (true, Some(banks)) = (mapped_banks, &report.banks) {
+                    println!("banks {}", banks);
+                }
+
+                if let Some(test) = test {
+                    if text {
+                        println!("test rmse: {}, mae: {}", test.rmse, test.mae);
+                    }
+                    report.set_test(test);
+                }
+            }
+            d => {
+                panic!("Unknown dataset {}", d)
+            }
+        }
+    }
+
+    report.wall_time_secs = start.elapsed().as_secs_f64();
+    match args.output_format {
+        OutputFormat::Text => {
+            if let Some(utilization) = &report.utilization {
+                print!("{}", utilization);
+            }
         }
+        OutputFormat::Json => println!("{}", report.to_json()),
+        OutputFormat::Csv => print!("{}", report.to_csv()),
     }
 }
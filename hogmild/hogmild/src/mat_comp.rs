@@ -1,50 +1,275 @@
-use ndarray::prelude::*;
-use ndarray_rand::{
-    rand::{rngs::StdRng, SeedableRng},
-    rand_distr::Uniform,
-    RandomExt,
+use std::{
+    collections::{HashMap, VecDeque},
+    marker::PhantomData,
+    sync::atomic::{AtomicU32, Ordering},
+    thread,
 };
 
+use ndarray::prelude::*;
+use ndarray_rand::{rand::rngs::StdRng, rand_distr::Uniform, RandomExt};
+use serde::Serialize;
+
 use crate::{
-    args::Args,
+    banks::BankStats,
+    compression::{CompressionConfig, CompressionMethod, Compressor},
+    conflicts::ConflictStats,
     data_structures::CoordListSparseMatrix,
-    simulator::{Sample, UpdateLogs},
+    dispatch::new_policy,
+    precision::{NumberFormat, Numeric, PrecisionConfig},
+    seed::{stream_rng, Stream},
+    shuffle::{sample_order, ShuffleConfig},
+    simulator::{run_simulation_with_policy, Sample, SimConfig, Tick, UpdatePart},
+    staleness::{StalenessHistogram, StalenessScaling, StalenessStats},
 };
 
-struct Weights {
-    x: Array2<f32>,
-    y: Array2<f32>,
-    xb: Array1<f32>,
-    yb: Array1<f32>,
+struct Weights<W: Numeric> {
+    x: Array2<W>,
+    y: Array2<W>,
+    xb: Array1<W>,
+    yb: Array1<W>,
 }
 
-impl Weights {
-    fn new(n_rows: usize, n_cols: usize, n_features: usize, seed: u64) -> Self {
-        let mut rng = StdRng::seed_from_u64(seed);
+impl<W: Numeric> Weights<W> {
+    /// Uniformly random weights, rounded to `W`.
+    fn new(
+        n_rows: usize,
+        n_cols: usize,
+        n_features: usize,
+        seed: u64,
+        precision: &PrecisionConfig,
+    ) -> Self {
+        let mut rng = stream_rng(seed, 0, Stream::Init);
+        let x = Array::random_using((n_rows, n_features), Uniform::new(-1., 1.), &mut rng);
+        let y = Array::random_using((n_cols, n_features), Uniform::new(-1., 1.), &mut rng);
+        let xb = Array::random_using(n_rows, Uniform::new(-1., 1.), &mut rng);
+        let yb = Array::random_using(n_cols, Uniform::new(-1., 1.), &mut rng);
+        let mut round = |v| W::from_f32(v, precision, &mut rng);
         Self {
-            x: Array::random_using((n_rows, n_features), Uniform::new(-1., 1.), &mut rng),
-            y: Array::random_using((n_cols, n_features), Uniform::new(-1., 1.), &mut rng),
-            xb: Array::random_using(n_rows, Uniform::new(-1., 1.), &mut rng),
-            yb: Array::random_using(n_cols, Uniform::new(-1., 1.), &mut rng),
+            x: x.mapv(&mut round),
+            y: y.mapv(&mut round),
+            xb: xb.mapv(&mut round),
+            yb: yb.mapv(&mut round),
         }
     }
+
+    /// The user row `u` and its bias.
+    fn user(&self, u: usize) -> (Array1<f32>, f32) {
+        (self.x.row(u).mapv(W::to_f32), self.xb[u].to_f32())
+    }
+
+    /// The movie row `v` and its bias.
+    fn movie(&self, v: usize) -> (Array1<f32>, f32) {
+        (self.y.row(v).mapv(W::to_f32), self.yb[v].to_f32())
+    }
+}
+
+/// A copy of `Weights` that can be read and written by many threads without
+/// locks. Every scalar is the `f32` value of a weight stored as the bits of
+/// an `AtomicU32`, and all accesses are `Relaxed`, so concurrent updates to
+/// the same row may overwrite each other exactly like in Hogwild!.
+struct SharedWeights {
+    n_features: usize,
+    x: Vec<AtomicU32>,
+    y: Vec<AtomicU32>,
+    xb: Vec<AtomicU32>,
+    yb: Vec<AtomicU32>,
 }
 
-struct GradUpdate {
+fn to_atomic<'a, W: Numeric>(vals: impl Iterator<Item = &'a W>) -> Vec<AtomicU32> {
+    vals.map(|v| AtomicU32::new(v.to_f32().to_bits())).collect()
+}
+
+fn load(a: &AtomicU32) -> f32 {
+    f32::from_bits(a.load(Ordering::Relaxed))
+}
+
+/// Unsynchronized read-modify-write rounded to `W`, lost updates are
+/// allowed.
+fn add<W: Numeric>(a: &AtomicU32, delta: f32, precision: &PrecisionConfig, rng: &mut StdRng) {
+    let sum = W::from_f32(load(a) + delta, precision, rng).to_f32();
+    a.store(sum.to_bits(), Ordering::Relaxed);
+}
+
+/// Add the `deltas` to a row and its bias, `vals`, rounding the sums to `W`.
+/// Returns the change made to the row and to the bias.
+fn accumulate<'a, 'b, W: Numeric, G: Numeric>(
+    precision: &PrecisionConfig,
+    rng: &mut StdRng,
+    vals: impl Iterator<Item = &'a mut W>,
+    deltas: impl Iterator<Item = &'b G>,
+) -> (Array1<f32>, f32) {
+    let mut changes: Vec<f32> = vals
+        .zip(deltas)
+        .map(|(v, d)| {
+            let (old, d) = (v.to_f32(), d.to_f32());
+            *v = W::from_f32(old + d, precision, rng);
+            if W::FORMAT == NumberFormat::F32 {
+                d
+            } else {
+                v.to_f32() - old
+            }
+        })
+        .collect();
+    let bias = changes.pop().unwrap();
+    (Array1::from(changes), bias)
+}
+
+impl SharedWeights {
+    fn new<W: Numeric>(weights: &Weights<W>, n_features: usize) -> Self {
+        Self {
+            n_features,
+            x: to_atomic(weights.x.iter()),
+            y: to_atomic(weights.y.iter()),
+            xb: to_atomic(weights.xb.iter()),
+            yb: to_atomic(weights.yb.iter()),
+        }
+    }
+
+    fn row(vals: &[AtomicU32], idx: usize, n_features: usize) -> Array1<f32> {
+        vals[idx * n_features..(idx + 1) * n_features]
+            .iter()
+            .map(load)
+            .collect()
+    }
+
+    fn xrow(&self, u: usize) -> Array1<f32> {
+        Self::row(&self.x, u, self.n_features)
+    }
+
+    fn ycol(&self, v: usize) -> Array1<f32> {
+        Self::row(&self.y, v, self.n_features)
+    }
+
+    fn fold<W: Numeric, G: Numeric>(
+        &self,
+        update: &GradUpdate<G>,
+        precision: &PrecisionConfig,
+        rng: &mut StdRng,
+    ) {
+        add::<W>(&self.xb[update.u], update.xb_grad.to_f32(), precision, rng);
+        add::<W>(&self.yb[update.v], update.yb_grad.to_f32(), precision, rng);
+
+        let x_start = update.u * self.n_features;
+        for (a, g) in self.x[x_start..].iter().zip(&update.xrow_grad) {
+            add::<W>(a, g.to_f32(), precision, rng);
+        }
+        let y_start = update.v * self.n_features;
+        for (a, g) in self.y[y_start..].iter().zip(&update.ycol_grad) {
+            add::<W>(a, g.to_f32(), precision, rng);
+        }
+    }
+
+    fn store_into<W: Numeric>(&self, weights: &mut Weights<W>, precision: &PrecisionConfig) {
+        let store = |dst: &mut [W], src: &[AtomicU32]| {
+            dst.iter_mut()
+                .zip(src)
+                .for_each(|(d, s)| *d = W::from_representable(load(s), precision));
+        };
+        store(weights.x.as_slice_mut().unwrap(), &self.x);
+        store(weights.y.as_slice_mut().unwrap(), &self.y);
+        store(weights.xb.as_slice_mut().unwrap(), &self.xb);
+        store(weights.yb.as_slice_mut().unwrap(), &self.yb);
+    }
+}
+
+/// Recent updates to the rows of one weight matrix and its biases, used to
+/// roll the current weights back to the version a stale sample read.
+struct DeltaHistory {
+    /// Per row, `(fold position, row delta, bias delta)` oldest first
+    rows: Vec<VecDeque<(usize, Array1<f32>, f32)>>,
+}
+
+impl DeltaHistory {
+    fn new(n_rows: usize) -> Self {
+        Self {
+            rows: vec![VecDeque::new(); n_rows],
+        }
+    }
+
+    fn push(&mut self, row: usize, position: usize, delta: Array1<f32>, bias_delta: f32) {
+        self.rows[row].push_back((position, delta, bias_delta));
+    }
+
+    /// Drop the deltas folded before `position`.
+    fn forget_before(&mut self, row: usize, position: usize) {
+        let deltas = &mut self.rows[row];
+        while deltas.front().is_some_and(|&(p, _, _)| p < position) {
+            deltas.pop_front();
+        }
+    }
+
+    /// The row and its bias as they were after the first `version` updates
+    /// were folded, given their current values.
+    fn rollback(
+        &self,
+        row: usize,
+        version: usize,
+        (mut vals, mut bias): (Array1<f32>, f32),
+    ) -> (Array1<f32>, f32) {
+        for (_, delta, bias_delta) in self.rows[row].iter().rev().take_while(|d| d.0 >= version) {
+            vals -= delta;
+            bias -= bias_delta;
+        }
+        (vals, bias)
+    }
+}
+
+#[derive(Clone)]
+struct GradUpdate<G: Numeric> {
     u: usize,
     v: usize,
-    xrow_grad: Array1<f32>,
-    ycol_grad: Array1<f32>,
-    xb_grad: f32,
-    yb_grad: f32,
+    xrow_grad: Array1<G>,
+    ycol_grad: Array1<G>,
+    xb_grad: G,
+    yb_grad: G,
     loss: f32,
 }
 
-pub struct MatrixCompletion {
-    matrix: CoordListSparseMatrix<f32>,
-    weights: Weights,
-    updates: Vec<Sample>,
+impl GradUpdate<f32> {
+    /// The update rounded to `G`.
+    fn round<G: Numeric>(&self, precision: &PrecisionConfig, rng: &mut StdRng) -> GradUpdate<G> {
+        let mut round = |v| G::from_f32(v, precision, rng);
+        GradUpdate {
+            u: self.u,
+            v: self.v,
+            xrow_grad: self.xrow_grad.mapv(&mut round),
+            ycol_grad: self.ycol_grad.mapv(&mut round),
+            xb_grad: round(self.xb_grad),
+            yb_grad: round(self.yb_grad),
+            loss: self.loss,
+        }
+    }
+}
 
+impl<G: Numeric> GradUpdate<G> {
+    /// Replace the row updates by what a worker compressing them sends,
+    /// rounded back to `G`. The movie rows follow the `n_users` user rows in
+    /// the compressor's keys.
+    fn compress(
+        &mut self,
+        compressor: &mut Compressor,
+        n_users: usize,
+        precision: &PrecisionConfig,
+        rng: &mut StdRng,
+    ) {
+        let mut round = |v| G::from_f32(v, precision, rng);
+        let mut x: Vec<f32> = self.xrow_grad.iter().map(|g| g.to_f32()).collect();
+        x.push(self.xb_grad.to_f32());
+        compressor.compress(self.u, &mut x);
+        self.xb_grad = round(x.pop().unwrap());
+        self.xrow_grad = x.into_iter().map(&mut round).collect();
+
+        let mut y: Vec<f32> = self.ycol_grad.iter().map(|g| g.to_f32()).collect();
+        y.push(self.yb_grad.to_f32());
+        compressor.compress(n_users + self.v, &mut y);
+        self.yb_grad = round(y.pop().unwrap());
+        self.ycol_grad = y.into_iter().map(&mut round).collect();
+    }
+}
+
+/// Hyper parameters of the matrix completion model.
+#[derive(Clone, Debug, Serialize)]
+pub struct MatrixCompletionConfig {
     /// Model hyper parameter initial learning rate
     pub alpha_0: f32,
     /// Model hyper parameter initial learning rate
@@ -53,6 +278,13 @@ pub struct MatrixCompletion {
     pub max_epoch: usize,
     /// When to stop training
     pub stopping_criterion: f32,
+    /// RNG seed for weights initialization
+    pub rng_seed: u64,
+    /// How the learning rate of every replayed update is scaled by its
+    /// staleness
+    pub staleness_scaling: StalenessScaling,
+    /// Decay rate of `StalenessScaling::Exponential`
+    pub staleness_decay: f32,
 
     /// Number of features in the decomposition matrix
     pub n_features: usize,
@@ -68,24 +300,130 @@ pub struct MatrixCompletion {
     pub lam_yb: f32,
 }
 
-impl MatrixCompletion {
-    pub fn new(args: &Args, matrix: CoordListSparseMatrix<f32>, updates: Vec<Sample>) -> Self {
+impl Default for MatrixCompletionConfig {
+    fn default() -> Self {
+        Self {
+            alpha_0: 0.1,
+            decay_rate: 5.,
+            max_epoch: 1000,
+            stopping_criterion: 0.001,
+            rng_seed: 4102000,
+            staleness_scaling: StalenessScaling::None,
+            staleness_decay: 0.1,
+            n_features: 10,
+            mu: 1.,
+            lam_xf: 1.,
+            lam_yf: 1.,
+            lam_xb: 1.,
+            lam_yb: 1.,
+        }
+    }
+}
+
+/// Why training stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StopReason {
+    /// The relative loss improvement fell below the stopping criterion
+    Converged,
+    /// The relative validation RMSE improvement fell below the stopping
+    /// criterion
+    ValidationConverged,
+    /// Ran for `max_epoch` epochs
+    MaxEpoch,
+}
+
+/// Prediction error on a held out set, in the units of the original ratings.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Metrics {
+    pub rmse: f32,
+    pub mae: f32,
+}
+
+/// Loss before training followed by the loss of every epoch.
+#[derive(Clone, Debug, Serialize)]
+pub struct TrainHistory {
+    pub losses: Vec<f32>,
+    /// Validation error before training and after every epoch, empty
+    /// without a validation set
+    pub validation: Vec<Metrics>,
+    /// Simulated cycles of every epoch trained, empty when not simulated
+    pub epoch_cycles: Vec<Tick>,
+    /// Staleness of the updates of every epoch trained, `None` when not
+    /// simulated
+    pub staleness: Option<StalenessStats>,
+    /// Row/column conflicts of every epoch trained, `None` unless the
+    /// schedule is simulated again every epoch
+    pub conflicts: Option<ConflictStats>,
+    /// Weight bank stalls of every epoch trained, `None` unless the schedule
+    /// is simulated again every epoch
+    pub banks: Option<BankStats>,
+    pub stop_reason: StopReason,
+}
+
+/// Matrix completion with the weights stored as `W` and the gradient updates
+/// computed as `G`.
+pub struct MatrixCompletion<W: Numeric = f32, G: Numeric = f32> {
+    matrix: CoordListSparseMatrix<f32>,
+    weights: Weights<W>,
+    updates: Vec<Sample>,
+    /// Compressor of every worker of the replayed schedules, kept across
+    /// epochs so that each worker's residuals carry over
+    compressors: HashMap<usize, Compressor>,
+    /// How values are rounded to `W` and `G`, and the format of the weights
+    /// the workers read
+    precision: PrecisionConfig,
+    _gradients: PhantomData<G>,
+
+    pub config: MatrixCompletionConfig,
+    /// Per epoch order of the samples
+    pub shuffle: ShuffleConfig,
+    /// How the workers compress their updates before they are folded
+    pub compression: CompressionConfig,
+    /// Simulate a new schedule for every epoch instead of replaying `updates`
+    pub resimulate: Option<SimConfig>,
+    /// Print the loss of every epoch as training goes
+    pub verbose: bool,
+    /// Held out entries used for reporting and early stopping
+    pub validation: Option<CoordListSparseMatrix<f32>>,
+    /// Size of one unit of the matrix entries in the original rating units,
+    /// `Metrics` are scaled by it
+    pub rating_scale: f32,
+}
+
+impl<W: Numeric, G: Numeric> MatrixCompletion<W, G> {
+    pub fn new(
+        config: MatrixCompletionConfig,
+        matrix: CoordListSparseMatrix<f32>,
+        updates: Vec<Sample>,
+    ) -> Self {
+        Self::with_precision(config, PrecisionConfig::default(), matrix, updates)
+    }
+
+    /// A model whose weights and gradients are rounded as `precision` says.
+    pub fn with_precision(
+        config: MatrixCompletionConfig,
+        precision: PrecisionConfig,
+        matrix: CoordListSparseMatrix<f32>,
+        updates: Vec<Sample>,
+    ) -> Self {
         let nrows = matrix.n_rows();
         let ncols = matrix.n_cols();
+        let seed = config.rng_seed;
         Self {
             matrix,
-            weights: Weights::new(nrows, ncols, args.n_features, args.rng_seed),
+            weights: Weights::new(nrows, ncols, config.n_features, seed, &precision),
             updates,
-            alpha_0: args.alpha_0,
-            decay_rate: args.decay_rate,
-            max_epoch: args.max_epoch,
-            stopping_criterion: args.stopping_criterion,
-            n_features: args.n_features,
-            mu: args.mu,
-            lam_xf: args.lam_xf,
-            lam_yf: args.lam_yf,
-            lam_xb: args.lam_xb,
-            lam_yb: args.lam_yb,
+            compressors: HashMap::new(),
+            precision,
+            _gradients: PhantomData,
+            config,
+            shuffle: ShuffleConfig::default(),
+            compression: CompressionConfig::default(),
+            resimulate: None,
+            verbose: true,
+            validation: None,
+            rating_scale: 1.,
         }
     }
 
@@ -93,43 +431,72 @@ impl MatrixCompletion {
         self.matrix
             .iter()
             .map(|&(row, col, entry)| {
-                let xrow = self.weights.x.slice(s![row, ..]);
-                let ycol = self.weights.y.slice(s![col, ..]);
-                let xb = self.weights.xb[row];
-                let yb = self.weights.yb[col];
+                let (xrow, xb) = self.weights.user(row);
+                let (ycol, yb) = self.weights.movie(col);
+                let (xrow, ycol) = (xrow.view(), ycol.view());
 
                 let nnzrow = self.matrix.nnz_row(row);
                 let nnzcol = self.matrix.nnz_col(col);
 
-                let e = error(entry, &xrow, &ycol, xb, yb, self.mu);
-                let x_regu = regu(&xrow.view(), self.lam_xf, nnzrow);
-                let y_regu = regu(&ycol.view(), self.lam_yf, nnzcol);
-                let xb_regu = xb * self.lam_xb / (nnzrow as f32);
-                let yb_regu = yb * self.lam_yb / (nnzcol as f32);
+                let e = error(entry, &xrow, &ycol, xb, yb, self.config.mu);
+                let x_regu = regu(&xrow.view(), self.config.lam_xf, nnzrow);
+                let y_regu = regu(&ycol.view(), self.config.lam_yf, nnzcol);
+                let xb_regu = xb * self.config.lam_xb / (nnzrow as f32);
+                let yb_regu = yb * self.config.lam_yb / (nnzcol as f32);
 
-                let loss = e.powi(2) + x_regu + y_regu + xb_regu + yb_regu;
-                loss
+                e.powi(2) + x_regu + y_regu + xb_regu + yb_regu
             })
             .sum()
     }
 
-    fn gradient(&self, sample_id: usize, learning_rate: f32) -> GradUpdate {
+    /// Error of the current weights on the entries of `matrix`, which must
+    /// have the same shape as the training matrix.
+    pub fn evaluate(&self, matrix: &CoordListSparseMatrix<f32>) -> Metrics {
+        let (mut se, mut ae) = (0., 0.);
+        for &(row, col, entry) in matrix.iter() {
+            let (xrow, xb) = self.weights.user(row);
+            let (ycol, yb) = self.weights.movie(col);
+            let e = error(entry, &xrow.view(), &ycol.view(), xb, yb, self.config.mu);
+            let e = e * self.rating_scale;
+            se += e * e;
+            ae += e.abs();
+        }
+        let n = matrix.nnz().max(1) as f32;
+        Metrics {
+            rmse: (se / n).sqrt(),
+            mae: ae / n,
+        }
+    }
+
+    /// Error on the validation set, if there is a non-empty one.
+    fn validate(&self) -> Option<Metrics> {
+        self.validation
+            .as_ref()
+            .filter(|m| m.nnz() > 0)
+            .map(|m| self.evaluate(m))
+    }
+
+    /// Gradient of one sample evaluated at the given weight values.
+    fn gradient_at(
+        &self,
+        sample_id: usize,
+        xrow: ArrayView1<f32>,
+        ycol: ArrayView1<f32>,
+        xb: f32,
+        yb: f32,
+        learning_rate: f32,
+    ) -> GradUpdate<f32> {
         // Forward prop
         let (row, col, entry) = self.matrix[sample_id];
 
-        let xrow = self.weights.x.slice(s![row, ..]);
-        let ycol = self.weights.y.slice(s![col, ..]);
-        let xb = self.weights.xb[row];
-        let yb = self.weights.yb[col];
-
         let nnzrow = self.matrix.nnz_row(row);
         let nnzcol = self.matrix.nnz_col(col);
 
-        let e = error(entry, &xrow, &ycol, xb, yb, self.mu);
-        let x_regu = regu(&xrow.view(), self.lam_xf, nnzrow);
-        let y_regu = regu(&ycol.view(), self.lam_yf, nnzcol);
-        let xb_regu = xb * self.lam_xb / (nnzrow as f32);
-        let yb_regu = yb * self.lam_yb / (nnzcol as f32);
+        let e = error(entry, &xrow, &ycol, xb, yb, self.config.mu);
+        let x_regu = regu(&xrow.view(), self.config.lam_xf, nnzrow);
+        let y_regu = regu(&ycol.view(), self.config.lam_yf, nnzcol);
+        let xb_regu = xb * self.config.lam_xb / (nnzrow as f32);
+        let yb_regu = yb * self.config.lam_yb / (nnzcol as f32);
 
         let loss = e.powi(2) + x_regu + y_regu + xb_regu + yb_regu;
 
@@ -151,57 +518,293 @@ impl MatrixCompletion {
         }
     }
 
-    fn fold(&mut self, updates: &[GradUpdate]) {
-        for update in updates {
-            self.weights.xb[update.u] += update.xb_grad;
-            self.weights.yb[update.v] += update.yb_grad;
+    /// Gradient of one sample as a worker computes it from the weights it
+    /// read, the user and movie rows with their biases, rounding them to the
+    /// format the workers read and the gradient to `G`.
+    fn worker_gradient(
+        &self,
+        sample_id: usize,
+        (mut xrow, xb): (Array1<f32>, f32),
+        (mut ycol, yb): (Array1<f32>, f32),
+        learning_rate: f32,
+        rng: &mut StdRng,
+    ) -> GradUpdate<G> {
+        let p = &self.precision;
+        p.round_all(p.weights, xrow.iter_mut().chain(ycol.iter_mut()), rng);
+        let (xb, yb) = (p.round(p.weights, xb, rng), p.round(p.weights, yb, rng));
+        let (x, y) = (xrow.view(), ycol.view());
+        let grad = self.gradient_at(sample_id, x, y, xb, yb, learning_rate);
+        grad.round(p, rng)
+    }
 
-            let x_idx = s![update.u, ..];
-            let new_x = &self.weights.x.slice(x_idx) + &update.xrow_grad;
-            self.weights.x.slice_mut(x_idx).assign(&new_x);
+    /// Fold the user row part of an update, returning the change it made to
+    /// the row and its bias.
+    fn fold_user(&mut self, update: &GradUpdate<G>, rng: &mut StdRng) -> (Array1<f32>, f32) {
+        let x = self.weights.x.row_mut(update.u);
+        let vals = x.into_iter().chain([&mut self.weights.xb[update.u]]);
+        let deltas = update.xrow_grad.iter().chain([&update.xb_grad]);
+        accumulate(&self.precision, rng, vals, deltas)
+    }
 
-            let y_idx = s![update.v, ..];
-            let new_y = &self.weights.y.slice(y_idx) + &update.ycol_grad;
-            self.weights.y.slice_mut(y_idx).assign(&new_y);
-        }
+    /// Same as `fold_user` for the movie row part.
+    fn fold_movie(&mut self, update: &GradUpdate<G>, rng: &mut StdRng) -> (Array1<f32>, f32) {
+        let y = self.weights.y.row_mut(update.v);
+        let vals = y.into_iter().chain([&mut self.weights.yb[update.v]]);
+        let deltas = update.ycol_grad.iter().chain([&update.yb_grad]);
+        accumulate(&self.precision, rng, vals, deltas)
     }
 
-    pub fn train(&mut self) -> Vec<f32> {
-        let mut history = vec![self.total_loss()];
-        println!("{}", history.last().unwrap());
+    /// Order in which the samples are visited during `epoch`. The `updates`
+    /// given to `new` must be simulated with the order of epoch 0.
+    pub fn sample_order(&self, epoch: usize) -> Vec<usize> {
+        sample_order(&self.shuffle, self.matrix.nnz(), Some(&self.matrix), epoch)
+    }
 
-        for i in 0..self.max_epoch {
-            let mut curr_loss = 0.;
+    /// Maps the sample ids of the simulated schedule to the samples to
+    /// visit in `epoch`, so that every epoch replays the same schedule over
+    /// a new permutation.
+    fn epoch_remap(&self, epoch: usize) -> Option<Vec<usize>> {
+        if !self.shuffle.is_shuffled() || epoch == 0 {
+            return None;
+        }
+        let mut remap = vec![0; self.matrix.nnz()];
+        for (&from, to) in self.sample_order(0).iter().zip(self.sample_order(epoch)) {
+            remap[from] = to;
+        }
+        Some(remap)
+    }
 
-            let learning_rate = self.alpha_0 / (1. + self.decay_rate * (i as f32));
-
-            let mut updates_idx = 0;
-            while updates_idx < self.updates.len() {
-                let mut gradients = vec![];
-                let curr_version = self.updates[updates_idx].weight_version;
-                while updates_idx < self.updates.len()
-                    && self.updates[updates_idx].weight_version == curr_version
-                {
-                    gradients
-                        .push(self.gradient(self.updates[updates_idx].sample_id, learning_rate));
-                    updates_idx += 1;
+    /// Run the epoch loop shared by all training modes. `epoch` performs one
+    /// pass over the data given the epoch number and learning rate and
+    /// returns its loss.
+    fn run_epochs(&mut self, mut epoch: impl FnMut(&mut Self, usize, f32) -> f32) -> TrainHistory {
+        let mut losses = vec![self.total_loss()];
+        let mut validation: Vec<Metrics> = self.validate().into_iter().collect();
+        self.print_epoch(*losses.last().unwrap(), validation.last());
+
+        let mut stop_reason = StopReason::MaxEpoch;
+        for i in 0..self.config.max_epoch {
+            let learning_rate = self.config.alpha_0 / (1. + self.config.decay_rate * (i as f32));
+            let curr_loss = epoch(self, i, learning_rate);
+            let curr_val = self.validate();
+            self.print_epoch(curr_loss, curr_val.as_ref());
+
+            let last_loss = *losses.last().unwrap();
+            losses.push(curr_loss);
+
+            // Early stopping follows the validation error when there is one
+            if let Some(curr_val) = curr_val {
+                let last_rmse = validation.last().unwrap().rmse;
+                validation.push(curr_val);
+                if improvement(last_rmse, curr_val.rmse) < self.config.stopping_criterion {
+                    stop_reason = StopReason::ValidationConverged;
+                    break;
                 }
-                curr_loss += gradients.iter().map(|grad| grad.loss).sum::<f32>();
-                self.fold(&gradients);
+            } else if improvement(last_loss, curr_loss) < self.config.stopping_criterion {
+                stop_reason = StopReason::Converged;
+                break;
             }
+        }
 
-            println!("{}", curr_loss);
+        TrainHistory {
+            losses,
+            validation,
+            epoch_cycles: vec![],
+            staleness: None,
+            conflicts: None,
+            banks: None,
+            stop_reason,
+        }
+    }
 
-            let last_loss = *history.last().unwrap();
-            history.push(curr_loss);
+    fn print_epoch(&self, loss: f32, validation: Option<&Metrics>) {
+        match (self.verbose, validation) {
+            (false, _) => {}
+            (true, None) => println!("{}", loss),
+            (true, Some(m)) => println!("{},{},{}", loss, m.rmse, m.mae),
+        }
+    }
 
-            if (last_loss - curr_loss) / last_loss < self.stopping_criterion {
-                break;
+    /// Train by replaying the simulated `updates` schedule every epoch, or a
+    /// freshly simulated one if `resimulate` is set. Updates are folded in
+    /// the simulated order, each computed against the exact weights it read
+    /// with its learning rate scaled by its staleness.
+    pub fn train(&mut self) -> TrainHistory {
+        let mut epoch_cycles = vec![];
+        let mut staleness = StalenessHistogram::default();
+        let mut conflicts = ConflictStats::default();
+        let mut banks = BankStats::default();
+        let compress = self.compression.method != CompressionMethod::None;
+        let mut rng = stream_rng(self.config.rng_seed, 0, Stream::Rounding);
+        let mut history = self.run_epochs(|this, epoch, learning_rate| {
+            let remap = match &this.resimulate {
+                Some(sim_config) => {
+                    let sim_config = SimConfig {
+                        epoch,
+                        ..sim_config.clone()
+                    };
+                    let (order, matrix) = (this.sample_order(epoch), Some(&this.matrix));
+                    let policy = new_policy(&sim_config, matrix);
+                    let (cycles, logs) =
+                        run_simulation_with_policy(&sim_config, order, policy, matrix);
+                    if let Some(stats) = &logs.conflicts {
+                        conflicts.add(stats);
+                    }
+                    banks.add(&logs.banks);
+                    this.updates = logs.samples;
+                    epoch_cycles.push(cycles);
+                    None
+                }
+                None => {
+                    epoch_cycles.push(this.updates.iter().map(|s| s.time).max().unwrap_or(0));
+                    this.epoch_remap(epoch)
+                }
+            };
+            let sample_id = |s: &Sample| remap.as_ref().map_or(s.sample_id, |r| r[s.sample_id]);
+
+            let updates = std::mem::take(&mut this.updates);
+            staleness.add_logs(&updates);
+
+            // Deltas older than every version still to be read are not needed
+            let mut oldest_read = vec![usize::MAX; updates.len() + 1];
+            for (j, s) in updates.iter().enumerate().rev() {
+                oldest_read[j] = oldest_read[j + 1].min(s.weight_version);
             }
-        }
+            let mut x_history = DeltaHistory::new(this.matrix.n_rows());
+            let mut y_history = DeltaHistory::new(this.matrix.n_cols());
+
+            // Update `j` in fold order read the weights after `weight_version`
+            // updates were folded, roll back what was folded since then. The
+            // updates of a message are computed before any of them is folded.
+            // A sample split over two shards computes its gradient at its
+            // first update and folds the rest of it at its second one
+            let mut curr_loss = 0.;
+            let mut split = HashMap::new();
+            let mut j = 0;
+            for message in updates.chunk_by(|a, b| (a.worker, a.batch) == (b.worker, b.batch)) {
+                let mut grads = vec![];
+                for (k, s) in message.iter().enumerate() {
+                    debug_assert!(s.weight_version <= j);
+                    let (scaling, decay) =
+                        (this.config.staleness_scaling, this.config.staleness_decay);
+                    let learning_rate =
+                        learning_rate * scaling.factor(j + k - s.weight_version, decay);
+                    let id = sample_id(s);
+                    let (row, col, _) = this.matrix[id];
+                    x_history.forget_before(row, oldest_read[j]);
+                    y_history.forget_before(col, oldest_read[j]);
+
+                    let grad = match split.remove(&id) {
+                        Some(grad) => grad,
+                        None => {
+                            let version = s.weight_version;
+                            let x = x_history.rollback(row, version, this.weights.user(row));
+                            let y = y_history.rollback(col, version, this.weights.movie(col));
+                            let mut grad = this.worker_gradient(id, x, y, learning_rate, &mut rng);
+                            curr_loss += grad.loss;
+                            // Every worker compresses its own updates. Compressors
+                            // are created when a worker first shows up, which
+                            // does not change their state as each only sees its
+                            // own updates. A split sample compresses both rows
+                            // here, as the worker sends them, so the residual of
+                            // its second part is the one from before the first
+                            // part was folded, on purpose
+                            if compress {
+                                let compressor = this
+                                    .compressors
+                                    .entry(s.worker)
+                                    .or_insert_with(|| Compressor::new(&this.compression));
+                                let n_users = this.matrix.n_rows();
+                                grad.compress(compressor, n_users, &this.precision, &mut rng);
+                            }
+                            if s.part != UpdatePart::Both {
+                                split.insert(id, grad.clone());
+                            }
+                            grad
+                        }
+                    };
+                    grads.push(grad);
+                }
 
+                for (s, grad) in message.iter().zip(grads) {
+                    let (row, col, _) = this.matrix[sample_id(s)];
+                    if s.part.has_row() {
+                        let (delta, bias_delta) = this.fold_user(&grad, &mut rng);
+                        x_history.push(row, j, delta, bias_delta);
+                    }
+                    if s.part.has_column() {
+                        let (delta, bias_delta) = this.fold_movie(&grad, &mut rng);
+                        y_history.push(col, j, delta, bias_delta);
+                    }
+                    j += 1;
+                }
+            }
+
+            this.updates = updates;
+            curr_loss
+        });
+        history.epoch_cycles = epoch_cycles;
+        history.staleness = Some(staleness.stats());
+        if self.resimulate.is_some() {
+            history.conflicts = Some(conflicts);
+            history.banks = Some(banks);
+        }
         history
     }
+
+    /// Train with real Hogwild! SGD: `n_workers` OS threads each take a
+    /// strided slice of the epoch's sample order and update the shared
+    /// weights without any locking. The simulated `updates` schedule is not
+    /// used.
+    pub fn train_hogwild(&mut self, n_workers: usize) -> TrainHistory {
+        let seed = self.config.rng_seed;
+        let shared = SharedWeights::new(&self.weights, self.config.n_features);
+        let compress = self.compression.method != CompressionMethod::None;
+        // Every thread has its own compressor and rounding stream
+        let mut workers: Vec<_> = (0..n_workers)
+            .map(|w| {
+                let rng = stream_rng(seed, 0, Stream::Thread(w));
+                (Compressor::new(&self.compression), rng)
+            })
+            .collect();
+
+        self.run_epochs(|this, epoch, learning_rate| {
+            let order = this.sample_order(epoch);
+            let model = &*this;
+            let (shared, order) = (&shared, &order);
+            let loss = thread::scope(|scope| {
+                let handles: Vec<_> = workers
+                    .iter_mut()
+                    .enumerate()
+                    .map(|(worker, (compressor, rng))| {
+                        scope.spawn(move || {
+                            let mut loss = 0.;
+                            for &sample_id in order.iter().skip(worker).step_by(n_workers) {
+                                let (row, col, _) = model.matrix[sample_id];
+                                let mut grad = model.worker_gradient(
+                                    sample_id,
+                                    (shared.xrow(row), load(&shared.xb[row])),
+                                    (shared.ycol(col), load(&shared.yb[col])),
+                                    learning_rate,
+                                    rng,
+                                );
+                                loss += grad.loss;
+                                let p = &model.precision;
+                                if compress {
+                                    grad.compress(compressor, model.matrix.n_rows(), p, rng);
+                                }
+                                shared.fold::<W, G>(&grad, p, rng);
+                            }
+                            loss
+                        })
+                    })
+                    .collect();
+                handles.into_iter().map(|h| h.join().unwrap()).sum()
+            });
+            shared.store_into(&mut this.weights, &this.precision);
+            loss
+        })
+    }
 }
 
 fn pred(x: &ArrayView1<f32>, y: &ArrayView1<f32>, xb: f32, yb: f32, mu: f32) -> f32 {
@@ -216,3 +819,106 @@ fn regu(x: &ArrayView1<f32>, lam: f32, nnz: usize) -> f32 {
     let x2 = x.mapv(|n| n.powi(2));
     x2.sum() * lam / (nnz as f32)
 }
+
+/// Relative improvement from `last` to `curr`, none once `last` is already 0.
+fn improvement(last: f32, curr: f32) -> f32 {
+    if last == 0. {
+        0.
+    } else {
+        (last - curr) / last
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compression::CompressionConfig,
+        precision::{Bf16, Fp16},
+    };
+
+    /// Two users and two movies, the four samples computed alternately by
+    /// two workers, one after the other.
+    fn model<W: Numeric, G: Numeric>(max_epoch: usize) -> MatrixCompletion<W, G> {
+        let mut matrix = CoordListSparseMatrix::new_empty();
+        (0..2).for_each(|_| matrix.add_row());
+        (0..2).for_each(|_| matrix.add_col());
+        for (i, j, r) in [(0, 0, 1.), (0, 1, -1.), (1, 0, 0.5), (1, 1, 2.)] {
+            matrix.insert(i, j, r);
+        }
+        let updates = (0..4)
+            .map(|j| Sample {
+                time: j as Tick,
+                sample_id: j,
+                weight_version: j,
+                worker: j % 2,
+                shard: 0,
+                part: UpdatePart::Both,
+                batch: j / 2,
+            })
+            .collect();
+        let config = MatrixCompletionConfig {
+            max_epoch,
+            decay_rate: 0.,
+            stopping_criterion: f32::NEG_INFINITY,
+            n_features: 3,
+            ..Default::default()
+        };
+        let mut model = MatrixCompletion::new(config, matrix, updates);
+        model.compression = CompressionConfig {
+            method: CompressionMethod::TopK,
+            top_k: 1,
+            error_feedback: true,
+        };
+        model.verbose = false;
+        model
+    }
+
+    #[test]
+    fn compression_residuals_carry_over_epochs_per_worker() {
+        let mut two_epochs = model::<f32, f32>(2);
+        two_epochs.train();
+        assert_eq!(two_epochs.compressors.len(), 2);
+
+        // One epoch at a time ends up the same only if every worker starts
+        // the second epoch with the residuals of its first
+        let mut one_by_one = model::<f32, f32>(1);
+        one_by_one.train();
+        one_by_one.train();
+        assert_eq!(one_by_one.weights.x, two_epochs.weights.x);
+        assert_eq!(one_by_one.weights.y, two_epochs.weights.y);
+
+        let mut forgetful = model::<f32, f32>(1);
+        forgetful.train();
+        forgetful.compressors.clear();
+        forgetful.train();
+        assert_ne!(forgetful.weights.y, two_epochs.weights.y);
+    }
+
+    fn losses<W: Numeric, G: Numeric>(mut model: MatrixCompletion<W, G>) -> Vec<f32> {
+        model.compression.method = CompressionMethod::None;
+        model.train().losses
+    }
+
+    #[test]
+    fn empty_validation_set_is_absent() {
+        let mut model = model::<f32, f32>(20);
+        model.config.stopping_criterion = 0.5;
+        model.validation = Some(CoordListSparseMatrix::new_with_shape_of(&model.matrix));
+        let history = model.train();
+        assert!(history.validation.is_empty());
+        assert_eq!(history.stop_reason, StopReason::Converged);
+        assert!(history.losses.iter().all(|l| l.is_finite()));
+    }
+
+    #[test]
+    fn reduced_precision_trains_close_to_f32() {
+        let exact = losses(model::<f32, f32>(5));
+        let reduced = losses(model::<Bf16, Fp16>(5));
+        assert!(reduced.last() < reduced.first());
+        assert_ne!(reduced, exact);
+        for (r, e) in reduced.iter().zip(&exact) {
+            assert!((r - e).abs() < 0.05 * e, "{r} {e}");
+        }
+    }
+}
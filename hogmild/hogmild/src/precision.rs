@@ -0,0 +1,329 @@
+use std::fmt::Debug;
+
+use clap::ValueEnum;
+use ndarray_rand::rand::{rngs::StdRng, Rng};
+use serde::Serialize;
+
+/// Number format a part of the datapath is emulated in, by the `Numeric`
+/// type of the same name. Arithmetic is done in `f32` and the results are
+/// rounded to the values the format can represent.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NumberFormat {
+    /// IEEE single precision, no rounding
+    F32,
+    /// 8 exponent and 7 mantissa bits
+    Bf16,
+    /// IEEE half precision, 5 exponent and 10 mantissa bits
+    Fp16,
+    /// Signed fixed point with `int_bits` and `frac_bits`
+    Fixed,
+}
+
+/// How a value between two representable ones is rounded.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Rounding {
+    /// To the nearest one, ties to even
+    Nearest,
+    /// Up or down with a probability proportional to the distance
+    Stochastic,
+}
+
+/// How the values of the `Numeric` types are rounded. The formats the
+/// weights are stored in and the gradients are computed in are the model's
+/// type parameters.
+#[derive(Clone, Debug)]
+pub struct PrecisionConfig {
+    /// Format of the weights the workers read
+    pub weights: NumberFormat,
+    /// Integer bits of `NumberFormat::Fixed`, not counting the sign
+    pub int_bits: u32,
+    /// Fraction bits of `NumberFormat::Fixed`
+    pub frac_bits: u32,
+    pub rounding: Rounding,
+}
+
+impl Default for PrecisionConfig {
+    fn default() -> Self {
+        Self {
+            weights: NumberFormat::F32,
+            int_bits: 7,
+            frac_bits: 8,
+            rounding: Rounding::Nearest,
+        }
+    }
+}
+
+impl PrecisionConfig {
+    /// `x` rounded to `format`. Values out of its range saturate.
+    pub fn round(&self, format: NumberFormat, x: f32, rng: &mut StdRng) -> f32 {
+        if !x.is_finite() {
+            return x;
+        }
+        match format {
+            NumberFormat::F32 => x,
+            // The largest finite bf16, f32::MAX with the low mantissa cleared
+            NumberFormat::Bf16 => self.round_float(x, 7, -126, f32::from_bits(0x7f7f_0000), rng),
+            NumberFormat::Fp16 => self.round_float(x, 10, -14, 65504., rng),
+            NumberFormat::Fixed => {
+                let ulp = (-(self.frac_bits as f32)).exp2();
+                let max = (self.int_bits as f32).exp2() - ulp;
+                self.round_to(x, ulp, rng).clamp(-max, max)
+            }
+        }
+    }
+
+    /// Round to a float with `mantissa_bits`, the smallest normal exponent
+    /// `min_exp` and the largest value `max`.
+    fn round_float(
+        &self,
+        x: f32,
+        mantissa_bits: i32,
+        min_exp: i32,
+        max: f32,
+        rng: &mut StdRng,
+    ) -> f32 {
+        if x == 0. {
+            return x;
+        }
+        let exp = (((x.to_bits() >> 23) & 0xff) as i32 - 127).max(min_exp);
+        let ulp = ((exp - mantissa_bits) as f32).exp2();
+        self.round_to(x, ulp, rng).clamp(-max, max)
+    }
+
+    /// Round to a multiple of `ulp`.
+    fn round_to(&self, x: f32, ulp: f32, rng: &mut StdRng) -> f32 {
+        let units = x / ulp;
+        let units = match self.rounding {
+            Rounding::Nearest => units.round_ties_even(),
+            Rounding::Stochastic => (units + rng.gen::<f32>()).floor(),
+        };
+        units * ulp
+    }
+
+    /// Round every value of `values` to `format`.
+    pub fn round_all<'a>(
+        &self,
+        format: NumberFormat,
+        values: impl IntoIterator<Item = &'a mut f32>,
+        rng: &mut StdRng,
+    ) {
+        if format != NumberFormat::F32 {
+            values
+                .into_iter()
+                .for_each(|v| *v = self.round(format, *v, rng));
+        }
+    }
+}
+
+/// A number type the model stores weights or gradients in.
+pub trait Numeric: Copy + Default + Debug + Send + Sync + 'static {
+    const FORMAT: NumberFormat;
+
+    /// `x`, which the type can represent exactly.
+    fn from_representable(x: f32, precision: &PrecisionConfig) -> Self;
+
+    fn to_f32(self) -> f32;
+
+    /// `x` rounded to the type.
+    fn from_f32(x: f32, precision: &PrecisionConfig, rng: &mut StdRng) -> Self {
+        Self::from_representable(precision.round(Self::FORMAT, x, rng), precision)
+    }
+}
+
+impl Numeric for f32 {
+    const FORMAT: NumberFormat = NumberFormat::F32;
+
+    fn from_representable(x: f32, _: &PrecisionConfig) -> Self {
+        x
+    }
+
+    fn to_f32(self) -> f32 {
+        self
+    }
+}
+
+/// The upper half of an `f32`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Bf16(u16);
+
+impl Numeric for Bf16 {
+    const FORMAT: NumberFormat = NumberFormat::Bf16;
+
+    fn from_representable(x: f32, _: &PrecisionConfig) -> Self {
+        if x.is_nan() {
+            return Bf16(0x7fc0);
+        }
+        Bf16((x.to_bits() >> 16) as u16)
+    }
+
+    fn to_f32(self) -> f32 {
+        f32::from_bits((self.0 as u32) << 16)
+    }
+}
+
+/// IEEE half precision bits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Fp16(u16);
+
+impl Numeric for Fp16 {
+    const FORMAT: NumberFormat = NumberFormat::Fp16;
+
+    fn from_representable(x: f32, _: &PrecisionConfig) -> Self {
+        let sign = ((x.to_bits() >> 16) & 0x8000) as u16;
+        let a = x.abs();
+        let bits = if a.is_nan() {
+            0x7e00
+        } else if a.is_infinite() {
+            0x7c00
+        } else if a < (-14f32).exp2() {
+            // Subnormal, a multiple of 2^-24
+            (a * 24f32.exp2()) as u16
+        } else {
+            let exp = ((a.to_bits() >> 23) as i32 - 127 + 15) as u16;
+            (exp << 10) | ((a.to_bits() >> 13) & 0x3ff) as u16
+        };
+        Fp16(sign | bits)
+    }
+
+    fn to_f32(self) -> f32 {
+        let sign = if self.0 & 0x8000 != 0 { -1. } else { 1. };
+        let (exp, mantissa) = ((self.0 >> 10) & 0x1f, self.0 & 0x3ff);
+        sign * match exp {
+            0 => mantissa as f32 * (-24f32).exp2(),
+            31 if mantissa == 0 => f32::INFINITY,
+            31 => f32::NAN,
+            _ => (1024 + mantissa) as f32 * (exp as f32 - 25.).exp2(),
+        }
+    }
+}
+
+/// Signed fixed point, the integer `raw` scaled by `2^-frac_bits`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Fixed {
+    raw: i32,
+    frac_bits: u8,
+}
+
+impl Numeric for Fixed {
+    const FORMAT: NumberFormat = NumberFormat::Fixed;
+
+    fn from_representable(x: f32, precision: &PrecisionConfig) -> Self {
+        let frac_bits = precision.frac_bits as u8;
+        Fixed {
+            raw: (x * (frac_bits as f32).exp2()) as i32,
+            frac_bits,
+        }
+    }
+
+    fn to_f32(self) -> f32 {
+        self.raw as f32 * (-(self.frac_bits as f32)).exp2()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray_rand::rand::SeedableRng;
+
+    fn config(rounding: Rounding) -> PrecisionConfig {
+        PrecisionConfig {
+            int_bits: 3,
+            frac_bits: 2,
+            rounding,
+            ..Default::default()
+        }
+    }
+
+    fn nearest(format: NumberFormat, x: f32) -> f32 {
+        let mut rng = StdRng::seed_from_u64(0);
+        config(Rounding::Nearest).round(format, x, &mut rng)
+    }
+
+    #[test]
+    fn ties_round_to_even() {
+        let e = |exp: i32| (exp as f32).exp2();
+        // Halfway between 1 and the next value goes down to the even 1, the
+        // next halfway point up to the even 1 + 2 ulps
+        assert_eq!(nearest(NumberFormat::Bf16, 1. + e(-8)), 1.);
+        assert_eq!(nearest(NumberFormat::Bf16, 1. + 3. * e(-8)), 1. + e(-6));
+        assert_eq!(nearest(NumberFormat::Bf16, 1. + e(-8) + e(-12)), 1. + e(-7));
+        assert_eq!(nearest(NumberFormat::Fp16, 1. + e(-11)), 1.);
+        assert_eq!(nearest(NumberFormat::Fp16, -1. - 3. * e(-11)), -1. - e(-9));
+        assert_eq!(nearest(NumberFormat::Fixed, 0.125), 0.);
+        assert_eq!(nearest(NumberFormat::Fixed, 0.375), 0.5);
+        assert_eq!(nearest(NumberFormat::F32, 1. + e(-23)), 1. + e(-23));
+    }
+
+    #[test]
+    fn subnormals_keep_the_smallest_exponent_ulp() {
+        let e = |exp: i32| (exp as f32).exp2();
+        assert_eq!(nearest(NumberFormat::Fp16, e(-24)), e(-24));
+        assert_eq!(nearest(NumberFormat::Fp16, 1.5 * e(-24)), e(-23));
+        assert_eq!(nearest(NumberFormat::Fp16, e(-25)), 0.);
+        assert_eq!(nearest(NumberFormat::Fp16, 0.75 * e(-24)), e(-24));
+        assert_eq!(nearest(NumberFormat::Fp16, e(-15) + e(-25)), e(-15));
+        assert_eq!(nearest(NumberFormat::Bf16, 1.5 * e(-133)), e(-132));
+        assert_eq!(nearest(NumberFormat::Bf16, 0.4 * e(-133)), 0.);
+    }
+
+    #[test]
+    fn out_of_range_values_saturate() {
+        assert_eq!(nearest(NumberFormat::Fp16, 1e6), 65504.);
+        assert_eq!(nearest(NumberFormat::Fp16, -65520.), -65504.);
+        let bf16_max = f32::from_bits(0x7f7f_0000);
+        assert_eq!(nearest(NumberFormat::Bf16, f32::MAX), bf16_max);
+        assert_eq!(nearest(NumberFormat::Bf16, -f32::MAX), -bf16_max);
+        assert_eq!(round_trip::<Bf16>(f32::MAX), bf16_max);
+        // 3 integer and 2 fraction bits
+        assert_eq!(nearest(NumberFormat::Fixed, 100.), 7.75);
+        assert_eq!(nearest(NumberFormat::Fixed, -8.), -7.75);
+        assert_eq!(nearest(NumberFormat::Fixed, 7.8), 7.75);
+        assert!(nearest(NumberFormat::Fp16, f32::INFINITY).is_infinite());
+    }
+
+    #[test]
+    fn stochastic_rounding_is_unbiased() {
+        let p = config(Rounding::Stochastic);
+        let mut rng = StdRng::seed_from_u64(0);
+        let n = 100000;
+        let draws: Vec<f32> = (0..n)
+            .map(|_| p.round(NumberFormat::Fixed, 1.3, &mut rng))
+            .collect();
+        assert!(draws.iter().all(|&d| d == 1.25 || d == 1.5));
+        let mean = draws.iter().sum::<f32>() / n as f32;
+        assert!((mean - 1.3).abs() < 2e-3, "{mean}");
+        // Representable values are left alone
+        assert_eq!(p.round(NumberFormat::Fixed, 1.25, &mut rng), 1.25);
+    }
+
+    fn round_trip<T: Numeric>(x: f32) -> f32 {
+        let p = config(Rounding::Nearest);
+        T::from_f32(x, &p, &mut StdRng::seed_from_u64(0)).to_f32()
+    }
+
+    #[test]
+    fn types_store_the_rounded_values() {
+        let values = [0., -0., 1., -2.5, 0.1, 1e-6, 3e-8, 1e-40, 7.3, 1e6, -65520.];
+        for x in values {
+            assert_eq!(round_trip::<f32>(x), x);
+            assert_eq!(round_trip::<Bf16>(x), nearest(NumberFormat::Bf16, x), "{x}");
+            assert_eq!(round_trip::<Fp16>(x), nearest(NumberFormat::Fp16, x), "{x}");
+            assert_eq!(
+                round_trip::<Fixed>(x),
+                nearest(NumberFormat::Fixed, x),
+                "{x}"
+            );
+        }
+        let p = config(Rounding::Nearest);
+        let bits = |x: f32| Fp16::from_representable(x, &p).0;
+        assert_eq!(
+            [bits(1.), bits(-2.), bits(65504.), bits((-24f32).exp2())],
+            [0x3c00, 0xc000, 0x7bff, 0x0001]
+        );
+        assert_eq!(Bf16::from_representable(1., &p).0, 0x3f80);
+        assert!(round_trip::<Fp16>(f32::NAN).is_nan());
+        assert!(round_trip::<Bf16>(f32::NAN).is_nan());
+    }
+}
@@ -0,0 +1,159 @@
+use std::fmt::Write;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    args::Args,
+    banks::BankStats,
+    conflicts::ConflictStats,
+    mat_comp::{Metrics, StopReason, TrainHistory},
+    simulator::Tick,
+    staleness::StalenessStats,
+    utilization::UtilizationReport,
+};
+
+/// Everything a run produced, in one machine-readable record.
+#[derive(Debug, Serialize)]
+pub struct RunReport<'a> {
+    pub config: &'a Args,
+    /// Simulated cycles of the first epoch's schedule, absent for real
+    /// hogwild runs
+    pub cycles: Option<Tick>,
+    /// Simulated cycles of every epoch trained
+    pub epoch_cycles: Vec<Tick>,
+    /// Simulated cycles summed over every epoch trained
+    pub total_cycles: Option<Tick>,
+    /// Staleness of the simulated updates over every epoch trained
+    pub staleness: Option<StalenessStats>,
+    /// Row/column conflicts of the simulated samples, over every epoch
+    /// trained when simulated again every epoch
+    pub conflicts: Option<ConflictStats>,
+    /// Weight bank stalls of the simulated samples, over every epoch trained
+    /// when simulated again every epoch
+    pub banks: Option<BankStats>,
+    /// Resource usage of the first simulated schedule
+    pub utilization: Option<UtilizationReport>,
+    /// Loss before training followed by the loss of every epoch
+    pub losses: Vec<f32>,
+    /// Validation RMSE before training and after every epoch
+    pub validation_rmse: Vec<f32>,
+    /// Validation MAE before training and after every epoch
+    pub validation_mae: Vec<f32>,
+    pub test_rmse: Option<f32>,
+    pub test_mae: Option<f32>,
+    pub stop_reason: Option<StopReason>,
+    pub wall_time_secs: f64,
+}
+
+impl<'a> RunReport<'a> {
+    pub fn new(config: &'a Args) -> Self {
+        Self {
+            config,
+            cycles: None,
+            epoch_cycles: vec![],
+            total_cycles: None,
+            staleness: None,
+            conflicts: None,
+            banks: None,
+            utilization: None,
+            losses: vec![],
+            validation_rmse: vec![],
+            validation_mae: vec![],
+            test_rmse: None,
+            test_mae: None,
+            stop_reason: None,
+            wall_time_secs: 0.,
+        }
+    }
+
+    pub fn set_history(&mut self, history: TrainHistory) {
+        self.losses = history.losses;
+        self.validation_rmse = history.validation.iter().map(|m| m.rmse).collect();
+        self.validation_mae = history.validation.iter().map(|m| m.mae).collect();
+        self.stop_reason = Some(history.stop_reason);
+        if !history.epoch_cycles.is_empty() {
+            self.cycles = Some(history.epoch_cycles[0]);
+            self.total_cycles = Some(history.epoch_cycles.iter().sum());
+        }
+        self.epoch_cycles = history.epoch_cycles;
+        self.staleness = history.staleness;
+        if history.conflicts.is_some() {
+            self.conflicts = history.conflicts;
+        }
+        if history.banks.is_some() {
+            self.banks = history.banks;
+        }
+    }
+
+    pub fn set_test(&mut self, metrics: Metrics) {
+        self.test_rmse = Some(metrics.rmse);
+        self.test_mae = Some(metrics.mae);
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    /// A header line and one record. Config fields are flattened into their
+    /// own columns, other nested fields into `parent.child` columns. Lists of
+    /// scalars are joined with `;`.
+    pub fn to_csv(&self) -> String {
+        let Value::Object(fields) = serde_json::to_value(self).unwrap() else {
+            unreachable!()
+        };
+
+        let mut columns = vec![];
+        for (key, val) in fields {
+            match val {
+                Value::Object(config) if key == "config" => {
+                    for (k, v) in config {
+                        flatten_csv(k, v, &mut columns);
+                    }
+                }
+                v => flatten_csv(key, v, &mut columns),
+            }
+        }
+
+        let (header, record): (Vec<_>, Vec<_>) = columns.into_iter().unzip();
+        let mut res = String::new();
+        writeln!(res, "{}", header.join(",")).unwrap();
+        writeln!(res, "{}", record.join(",")).unwrap();
+        res
+    }
+}
+
+fn flatten_csv(key: String, val: Value, columns: &mut Vec<(String, String)>) {
+    match val {
+        Value::Object(fields) => {
+            for (k, v) in fields {
+                flatten_csv(format!("{}.{}", key, k), v, columns);
+            }
+        }
+        Value::Array(vals) if vals.iter().all(|v| !v.is_array() && !v.is_object()) => {
+            let joined = vals.iter().map(csv_field).collect::<Vec<_>>();
+            columns.push((key, joined.join(";")));
+        }
+        Value::Array(vals) => {
+            let nested = Value::String(Value::Array(vals).to_string());
+            columns.push((key, csv_field(&nested)));
+        }
+        v => columns.push((key, csv_field(&v))),
+    }
+}
+
+fn csv_field(val: &Value) -> String {
+    match val {
+        Value::Null => String::new(),
+        Value::String(s) if s.contains([',', '"', '\n']) => {
+            format!("\"{}\"", s.replace('"', "\"\""))
+        }
+        Value::String(s) => s.clone(),
+        // Floats are widened to f64 by `to_value`, print f32 values as such
+        Value::Number(n) => match n.as_f64() {
+            Some(f) if n.is_f64() && f64::from(f as f32) == f => (f as f32).to_string(),
+            _ => n.to_string(),
+        },
+        v => v.to_string(),
+    }
+}
@@ -0,0 +1,74 @@
+//! Independent random streams derived from the single `--rng-seed`.
+
+use ndarray_rand::rand::{rngs::StdRng, SeedableRng};
+
+/// What a random stream is drawn for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stream {
+    /// Initial weights
+    Init,
+    /// Stochastic rounding of the replayed updates
+    Rounding,
+    /// Stochastic rounding of one Hogwild! thread
+    Thread(usize),
+    /// Held out entries
+    Split,
+    /// Sample order
+    Shuffle,
+    /// Timing of one simulated worker
+    Worker(usize),
+    /// Network delays of the simulated parameter server
+    Server,
+    /// Random dispatch
+    Dispatch,
+}
+
+impl Stream {
+    fn id(self) -> u64 {
+        let (kind, index) = match self {
+            Stream::Init => (0, 0),
+            Stream::Rounding => (1, 0),
+            Stream::Thread(i) => (2, i),
+            Stream::Split => (3, 0),
+            Stream::Shuffle => (4, 0),
+            Stream::Worker(i) => (5, i),
+            Stream::Server => (6, 0),
+            Stream::Dispatch => (7, 0),
+        };
+        (kind << 32) ^ index as u64
+    }
+}
+
+/// SplitMix64 finalizer, so that nearby inputs give unrelated outputs.
+fn mix(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// The generator of `stream` in `epoch`, unrelated to every other stream
+/// and epoch of the same `seed`.
+pub fn stream_rng(seed: u64, epoch: usize, stream: Stream) -> StdRng {
+    StdRng::seed_from_u64(mix(mix(mix(seed) ^ epoch as u64) ^ stream.id()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray_rand::rand::Rng;
+
+    #[test]
+    fn streams_do_not_overlap() {
+        let first = |epoch, stream| stream_rng(4102000, epoch, stream).gen::<u64>();
+        assert_eq!(first(1, Stream::Worker(2)), first(1, Stream::Worker(2)));
+        // Neighbouring seeds, epochs and indices used to share streams
+        assert_ne!(first(1, Stream::Worker(0)), first(0, Stream::Worker(1)));
+        assert_ne!(first(1, Stream::Server), first(0, Stream::Dispatch));
+        assert_ne!(first(0, Stream::Init), first(0, Stream::Shuffle));
+        assert_ne!(first(0, Stream::Rounding), first(1, Stream::Shuffle));
+        assert_ne!(first(0, Stream::Thread(0)), first(0, Stream::Worker(0)));
+        let other_seed = stream_rng(4102001, 0, Stream::Worker(0)).gen::<u64>();
+        assert_ne!(other_seed, first(0, Stream::Worker(1)));
+    }
+}
@@ -0,0 +1,186 @@
+use std::{collections::HashMap, fmt::Display};
+
+use clap::ValueEnum;
+use ndarray_rand::rand::{rngs::StdRng, seq::SliceRandom};
+use serde::Serialize;
+
+use crate::{
+    data_structures::CoordListSparseMatrix,
+    seed::{stream_rng, Stream},
+};
+
+/// Order in which samples are sent to the workers every epoch.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ShuffleStrategy {
+    /// Keep the order of the loader
+    None,
+    /// A uniformly random permutation
+    Full,
+    /// Shuffle the order of contiguous blocks, keeping the order inside them
+    Block,
+    /// Interleave the rows so that consecutive samples come from different rows
+    ByRow,
+    /// Interleave the columns so that consecutive samples come from different
+    /// columns
+    ByColumn,
+}
+
+#[derive(Clone, Debug)]
+pub struct ShuffleConfig {
+    pub strategy: ShuffleStrategy,
+    /// Number of samples per block for `ShuffleStrategy::Block`
+    pub block_size: usize,
+    /// RNG seed, combined with the epoch number
+    pub seed: u64,
+}
+
+impl Default for ShuffleConfig {
+    fn default() -> Self {
+        Self {
+            strategy: ShuffleStrategy::None,
+            block_size: 1024,
+            seed: 4102000,
+        }
+    }
+}
+
+impl ShuffleConfig {
+    pub fn is_shuffled(&self) -> bool {
+        self.strategy != ShuffleStrategy::None
+    }
+}
+
+/// Round robin over the groups, after shuffling the order of the groups and
+/// of the samples within each of them.
+fn interleave(mut groups: Vec<Vec<usize>>, rng: &mut StdRng) -> Vec<usize> {
+    groups.sort_unstable();
+    groups.shuffle(rng);
+    for group in groups.iter_mut() {
+        group.shuffle(rng);
+    }
+
+    let longest = groups.iter().map(Vec::len).max().unwrap_or(0);
+    (0..longest)
+        .flat_map(|i| groups.iter().filter_map(move |g| g.get(i).copied()))
+        .collect()
+}
+
+fn group_by(keys: impl Iterator<Item = usize>) -> Vec<Vec<usize>> {
+    let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (i, key) in keys.enumerate() {
+        groups.entry(key).or_default().push(i);
+    }
+    groups.into_values().collect()
+}
+
+/// The ids of `num_samples` samples in the order they are sent during
+/// `epoch`. The same arguments always produce the same order. The stratified
+/// strategies need the coordinates of the samples in `matrix`.
+pub fn sample_order<Elem: Copy + Display>(
+    config: &ShuffleConfig,
+    num_samples: usize,
+    matrix: Option<&CoordListSparseMatrix<Elem>>,
+    epoch: usize,
+) -> Vec<usize> {
+    let mut rng = stream_rng(config.seed, epoch, Stream::Shuffle);
+    let mut order: Vec<usize> = (0..num_samples).collect();
+    let coords = || {
+        let matrix = matrix.expect("stratified shuffling requires the sample coordinates");
+        assert_eq!(matrix.nnz(), num_samples);
+        matrix.iter()
+    };
+
+    match config.strategy {
+        ShuffleStrategy::None => order,
+        ShuffleStrategy::Full => {
+            order.shuffle(&mut rng);
+            order
+        }
+        ShuffleStrategy::Block => {
+            let mut blocks: Vec<&[usize]> = order.chunks(config.block_size.max(1)).collect();
+            blocks.shuffle(&mut rng);
+            blocks.concat()
+        }
+        ShuffleStrategy::ByRow => interleave(group_by(coords().map(|e| e.0)), &mut rng),
+        ShuffleStrategy::ByColumn => interleave(group_by(coords().map(|e| e.1)), &mut rng),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 5 rows and 4 columns, all entries present, in row major order.
+    fn matrix() -> CoordListSparseMatrix<f32> {
+        let mut matrix = CoordListSparseMatrix::new_empty();
+        (0..5).for_each(|_| matrix.add_row());
+        (0..4).for_each(|_| matrix.add_col());
+        for i in 0..20 {
+            matrix.insert(i / 4, i % 4, 1.);
+        }
+        matrix
+    }
+
+    fn order(strategy: ShuffleStrategy, epoch: usize) -> Vec<usize> {
+        let config = ShuffleConfig {
+            strategy,
+            block_size: 4,
+            ..Default::default()
+        };
+        sample_order(&config, 20, Some(&matrix()), epoch)
+    }
+
+    #[test]
+    fn every_strategy_is_a_permutation() {
+        for strategy in ShuffleStrategy::value_variants() {
+            let mut order = order(*strategy, 0);
+            order.sort_unstable();
+            assert_eq!(order, (0..20).collect::<Vec<_>>(), "{strategy:?}");
+        }
+    }
+
+    #[test]
+    fn orders_depend_only_on_seed_and_epoch() {
+        assert_eq!(order(ShuffleStrategy::None, 3), (0..20).collect::<Vec<_>>());
+        assert_eq!(
+            order(ShuffleStrategy::Full, 1),
+            order(ShuffleStrategy::Full, 1)
+        );
+        assert_ne!(
+            order(ShuffleStrategy::Full, 1),
+            order(ShuffleStrategy::Full, 2)
+        );
+    }
+
+    #[test]
+    fn blocks_keep_their_order() {
+        for block in order(ShuffleStrategy::Block, 0).chunks(4) {
+            assert_eq!(block[0] % 4, 0);
+            assert!(block.windows(2).all(|w| w[1] == w[0] + 1));
+        }
+    }
+
+    #[test]
+    fn stratified_orders_interleave() {
+        let m = matrix();
+        let rows: Vec<usize> = order(ShuffleStrategy::ByRow, 0)
+            .iter()
+            .map(|&i| m[i].0)
+            .collect();
+        for round in rows.chunks(5) {
+            let mut round = round.to_vec();
+            round.sort_unstable();
+            assert_eq!(round, (0..5).collect::<Vec<_>>());
+        }
+        let cols: Vec<usize> = order(ShuffleStrategy::ByColumn, 0)
+            .iter()
+            .map(|&i| m[i].1)
+            .collect();
+        for round in cols.chunks(4) {
+            let mut round = round.to_vec();
+            round.sort_unstable();
+            assert_eq!(round, (0..4).collect::<Vec<_>>());
+        }
+    }
+}
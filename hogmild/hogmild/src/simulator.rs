@@ -1,24 +1,215 @@
-use std::{collections::VecDeque, fmt};
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt,
+};
 
-use crate::args::Args;
+use ndarray_rand::rand::{rngs::StdRng, Rng};
+
+use crate::banks::{partition_rows, BankMapping, BankStats, Partition};
+use crate::conflicts::{ConflictStats, ConflictTracker};
+use crate::consistency::{Consistency, ConsistencyGate};
+use crate::data_structures::CoordListSparseMatrix;
+use crate::delay::{DelayModel, DelaySampler};
+use crate::dispatch::{new_policy, DispatchPolicy, DispatchStrategy};
+use crate::seed::{stream_rng, Stream};
+use crate::trace::{Component, EventKind, Trace};
 
 pub type Tick = u64;
 
-#[derive(Clone, Copy)]
+/// Hardware timing and topology of the simulated accelerator.
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+    /// Number of banks to separate the weights into
+    pub n_weight_banks: usize,
+    /// Which banks a sample reads, mapping them needs the matrix coordinates
+    pub bank_mapping: BankMapping,
+    /// Number of worker threads in async sgd
+    pub n_workers: usize,
+    /// Number of gradient folds that can happen in parallel, per shard
+    pub n_folders: usize,
+    /// Number of parameter server shards, each with its own fold pipeline.
+    /// More than one needs the matrix coordinates
+    pub n_shards: usize,
+    /// Which shard owns every user and movie row
+    pub shard_mapping: Partition,
+    /// Fifo depth in async sgd
+    pub fifo_depth: usize,
+    /// Number of samples a worker takes in before computing their combined
+    /// update, the sum of their gradients, and sending it as one message
+    pub batch_size: usize,
+    /// How the parameter server picks the worker each sample is sent to
+    pub dispatch: DispatchStrategy,
+    /// When the matrix coordinates are given, hold back samples sharing a
+    /// row or column with one in flight and send the first conflict free one
+    /// among the next `conflict_lookahead` instead. 0 sends them in order
+    pub conflict_lookahead: usize,
+    /// When the parameter server may send the next sample
+    pub consistency: Consistency,
+    /// Largest number of samples folded between the weights a sample reads
+    /// and its updates under `Consistency::Ssp`
+    pub staleness_bound: usize,
+
+    /// Time to send a sample or the update of a single sample. A batch
+    /// update takes longer the more rows it changes
+    pub send_delay: Tick,
+    /// Size of the update of a row relative to an uncompressed one, the
+    /// time to send it scales with it
+    pub update_size: f64,
+    /// Time to deliver a sample/update, the mean of the delay models
+    pub network_delay: Tick,
+    /// Delay model of the link from the parameter server to the workers
+    pub downlink_delay: DelayModel,
+    /// Delay model of the links from the workers to the parameter server
+    pub uplink_delay: DelayModel,
+    /// Time to receive a sample/update
+    pub receive_delay: Tick,
+    /// Initiation interval of gradient calculation
+    pub gradient_ii: Tick,
+    /// Latency of calculating one gradient
+    pub gradient_latency: Tick,
+    /// Initiation interval of folding gradient updates
+    pub fold_ii: Tick,
+    /// Latency of folding one gradient update
+    pub fold_latency: Tick,
+
+    /// Per worker `gradient_ii`, empty to use it for every worker
+    pub worker_gradient_ii: Vec<Tick>,
+    /// Per worker `gradient_latency`, empty to use it for every worker
+    pub worker_gradient_latency: Vec<Tick>,
+    /// Scale every worker's gradient latency by a random factor drawn once
+    /// from [1 - spread, 1 + spread]
+    pub gradient_latency_spread: f64,
+    /// Maximum random extra latency added to every gradient
+    pub gradient_jitter: Tick,
+    /// Probability that a gradient straggles
+    pub straggler_prob: f64,
+    /// Factor by which a straggling gradient's latency is multiplied
+    pub straggler_slowdown: f64,
+    /// RNG seed for the random timing
+    pub seed: u64,
+    /// Epoch the schedule is simulated for, each drawing different timing
+    pub epoch: usize,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            n_weight_banks: 8,
+            bank_mapping: BankMapping::Pool,
+            n_workers: 8,
+            n_folders: 8,
+            n_shards: 1,
+            shard_mapping: Partition::Modulo,
+            fifo_depth: 8,
+            batch_size: 1,
+            dispatch: DispatchStrategy::InOrder,
+            conflict_lookahead: 0,
+            consistency: Consistency::Async,
+            staleness_bound: 16,
+            send_delay: 4,
+            update_size: 1.,
+            network_delay: 8,
+            downlink_delay: DelayModel::default(),
+            uplink_delay: DelayModel::default(),
+            receive_delay: 4,
+            gradient_ii: 8,
+            gradient_latency: 32,
+            fold_ii: 8,
+            fold_latency: 32,
+            worker_gradient_ii: vec![],
+            worker_gradient_latency: vec![],
+            gradient_latency_spread: 0.,
+            gradient_jitter: 0,
+            straggler_prob: 0.,
+            straggler_slowdown: 4.,
+            seed: 4102000,
+            epoch: 0,
+        }
+    }
+}
+
+impl SimConfig {
+    /// Initiation interval of gradient calculation of one worker.
+    pub fn gradient_ii_of(&self, worker: usize) -> Tick {
+        self.worker_gradient_ii
+            .get(worker)
+            .copied()
+            .unwrap_or(self.gradient_ii)
+    }
+
+    fn validate(&self) {
+        assert!(self.batch_size > 0, "batch_size must be positive");
+        for (name, list) in [
+            ("worker_gradient_ii", &self.worker_gradient_ii),
+            ("worker_gradient_latency", &self.worker_gradient_latency),
+        ] {
+            assert!(
+                list.is_empty() || list.len() == self.n_workers,
+                "{} must have one entry per worker",
+                name
+            );
+        }
+    }
+}
+
+/// Which weights of a sample an update folds. With shards the user row and
+/// the movie row can be owned by different shards, each folding its part.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpdatePart {
+    Both,
+    Row,
+    Column,
+}
+
+impl UpdatePart {
+    pub fn has_row(self) -> bool {
+        self != UpdatePart::Column
+    }
+
+    pub fn has_column(self) -> bool {
+        self != UpdatePart::Row
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
 pub struct Sample {
     pub time: Tick,
     pub sample_id: usize,
     pub weight_version: usize,
+    /// The worker that computed the update
+    pub worker: usize,
+    /// The shard that folds the update
+    pub shard: usize,
+    pub part: UpdatePart,
+    /// The batch of the worker the update was sent in, numbered per worker
+    pub batch: usize,
+}
+
+/// The updates a worker sends to one shard as a single message, its part of
+/// the combined update of a batch of samples.
+#[derive(Clone, Debug)]
+struct Message {
+    time: Tick,
+    updates: Vec<Sample>,
 }
 
 pub struct UpdateLogs {
+    /// Updates in the order they were folded into the weights. A sample
+    /// whose rows are owned by two shards has one update per shard, and
+    /// `weight_version` counts updates of every shard
     pub samples: Vec<Sample>,
+    /// Row/column conflicts of the samples, when the matrix coordinates are
+    /// given
+    pub conflicts: Option<ConflictStats>,
+    pub banks: BankStats,
 }
 
 impl UpdateLogs {
     fn with_capacity(capacity: usize) -> Self {
         Self {
             samples: Vec::with_capacity(capacity),
+            conflicts: None,
+            banks: BankStats::default(),
         }
     }
 
@@ -37,10 +228,6 @@ impl UpdateLogs {
             .max()
             .unwrap_or(Tick::MAX)
     }
-
-    fn sort(&mut self) {
-        self.samples.sort_by_key(|s| s.weight_version);
-    }
 }
 
 impl fmt::Display for UpdateLogs {
@@ -53,128 +240,513 @@ impl fmt::Display for UpdateLogs {
     }
 }
 
-pub fn run_simulation(args: &Args, num_samples: usize) -> (Tick, UpdateLogs) {
-    let mut params_server = ParamsServerState::new(args, num_samples);
+pub fn run_simulation(config: &SimConfig, num_samples: usize) -> (Tick, UpdateLogs) {
+    run_simulation_with_order(config, (0..num_samples).collect())
+}
+
+/// Simulate sending the samples in the given order, `order[i]` being the id
+/// of the `i`th sample sent.
+pub fn run_simulation_with_order(config: &SimConfig, order: Vec<usize>) -> (Tick, UpdateLogs) {
+    let policy = new_policy(config, None);
+    run_simulation_with_policy(config, order, policy, None)
+}
+
+/// Same as `run_simulation_with_order`, dispatching the samples with
+/// `policy` instead of the one `config.dispatch` names. Given the `matrix`
+/// the sample ids index into, row/column conflicts are counted and avoided.
+pub fn run_simulation_with_policy(
+    config: &SimConfig,
+    order: Vec<usize>,
+    policy: Box<dyn DispatchPolicy>,
+    matrix: Option<&CoordListSparseMatrix<f32>>,
+) -> (Tick, UpdateLogs) {
+    let (tick, update_logs, _) = simulate(config, order, policy, matrix, Trace::disabled(), true);
+    (tick, update_logs)
+}
+
+/// Same as `run_simulation_with_policy`, also recording every event.
+pub fn run_simulation_with_trace(
+    config: &SimConfig,
+    order: Vec<usize>,
+    policy: Box<dyn DispatchPolicy>,
+    matrix: Option<&CoordListSparseMatrix<f32>>,
+) -> (Tick, UpdateLogs, Trace) {
+    simulate(config, order, policy, matrix, Trace::enabled(), true)
+}
+
+/// Same as `run_simulation_with_order`, but stepping through every single
+/// tick instead of jumping to the next one where something happens. Slow,
+/// kept as the reference the event driven engine is checked against.
+pub fn run_simulation_stepped(config: &SimConfig, order: Vec<usize>) -> (Tick, UpdateLogs) {
+    let policy = new_policy(config, None);
+    let (tick, update_logs, _) = simulate(config, order, policy, None, Trace::disabled(), false);
+    (tick, update_logs)
+}
+
+/// The first tick after `tick` where any component could act, given that
+/// nothing happened during `tick`. Every condition the components wait on
+/// is a stored tick, or a FIFO changing which only happens on active ticks.
+fn next_event_tick(
+    tick: Tick,
+    params_server: &ParamsServerState,
+    workers: &[WorkerState],
+    sample_chans: &[VecDeque<Sample>],
+    update_chans: &[Vec<VecDeque<Message>>],
+) -> Tick {
+    let mut candidates = vec![];
+    candidates.extend(params_server.bank_free_at.iter().copied());
+    candidates.extend(params_server.weight_version_queue.front().map(|&(t, _)| t));
+    for update_rxs in update_chans {
+        for (shard, update_rx) in update_rxs.iter().enumerate() {
+            if let Some(s) = update_rx.front() {
+                candidates.push(params_server.next_fold_at(shard, s.time.max(tick + 1)));
+            }
+        }
+    }
+    for (worker, sample_rx) in workers.iter().zip(sample_chans) {
+        let received = worker.receive_ready_at;
+        if let Some(s) = sample_rx.front() {
+            candidates.push(s.time.max(received));
+            candidates.push(s.time.max(received).max(worker.next_ready));
+        }
+        if !worker.batch.is_empty() {
+            candidates.push(received.max(worker.next_ready));
+        }
+    }
+    candidates
+        .into_iter()
+        .filter(|&t| t > tick)
+        .min()
+        .expect("simulation is stuck")
+}
+
+fn simulate(
+    config: &SimConfig,
+    order: Vec<usize>,
+    policy: Box<dyn DispatchPolicy>,
+    matrix: Option<&CoordListSparseMatrix<f32>>,
+    mut trace: Trace,
+    event_driven: bool,
+) -> (Tick, UpdateLogs, Trace) {
+    config.validate();
+    let shard_map = (config.n_shards > 1).then(|| {
+        let matrix = matrix.expect("sharding needs the matrix coordinates");
+        partition_rows(config.shard_mapping, matrix, config.n_shards)
+    });
+    let shard_map = shard_map.as_deref();
+    let mut params_server = ParamsServerState::new(config, order, policy, shard_map);
+    params_server.conflicts = matrix.map(ConflictTracker::new);
+    if let Some(partition) = config.bank_mapping.partition() {
+        let matrix = matrix.expect("mapping weight banks needs the matrix coordinates");
+        let banks = partition_rows(partition, matrix, config.n_weight_banks);
+        params_server.sample_banks = Some(banks);
+    }
     let (mut workers, mut sample_chans, mut update_chans) = (vec![], vec![], vec![]);
-    for _ in 0..args.n_workers {
-        workers.push(WorkerState::new(args));
-        sample_chans.push(VecDeque::with_capacity(args.fifo_depth));
-        update_chans.push(VecDeque::with_capacity(args.fifo_depth));
+    for i in 0..config.n_workers {
+        workers.push(WorkerState::new(config, i, shard_map, matrix));
+        sample_chans.push(VecDeque::with_capacity(config.fifo_depth));
+        update_chans.push(vec![
+            VecDeque::<Message>::with_capacity(config.fifo_depth);
+            config.n_shards
+        ]);
     }
 
     while !params_server.finished_receiving() {
-        let samples = params_server.tick_server(&sample_chans, &mut update_chans);
-        for i in 0..args.n_workers {
-            workers[i].tick_worker(&mut sample_chans[i], &mut update_chans[i]);
+        let tick = params_server.tick;
+        let (samples, received) =
+            params_server.tick_server(&sample_chans, &mut update_chans, &mut trace);
+        let mut active = received || !samples.is_empty();
+        // Workers stop waiting for their batch to fill once no sample can be
+        // sent, or they would hold back the updates that sending waits on
+        let stuck = params_server.next_sendable().is_none();
+        for i in 0..config.n_workers {
+            let flush = stuck && samples.iter().all(|&(w, _)| w != i);
+            let (sample_rx, update_txs) = (&mut sample_chans[i], &mut update_chans[i]);
+            active |= workers[i].tick_worker(sample_rx, update_txs, flush, &mut trace);
         }
         for (i, sample) in samples {
+            let c = Component::Worker(i);
+            trace.record(
+                tick,
+                0,
+                c,
+                EventKind::SampleFifoPush,
+                Some(sample.sample_id),
+            );
             sample_chans[i].push_back(sample);
         }
+
+        if event_driven && !active && !params_server.finished_receiving() {
+            let next =
+                next_event_tick(tick, &params_server, &workers, &sample_chans, &update_chans);
+            params_server.skip_to(next);
+            for worker in workers.iter_mut() {
+                worker.tick = next;
+            }
+        }
     }
 
-    params_server.cleanup();
-    (params_server.tick, params_server.update_logs)
+    params_server.cleanup(&mut trace);
+    (params_server.tick, params_server.update_logs, trace)
 }
 
 struct WorkerState<'a> {
-    args: &'a Args,
+    config: &'a SimConfig,
+    id: usize,
+    /// This worker's initiation interval of gradient calculation
+    gradient_ii: Tick,
+    /// This worker's gradient latency before jitter and straggling
+    gradient_latency: Tick,
+    /// Draws the random part of the gradient latencies and uplink delays
+    rng: StdRng,
+    uplink: DelaySampler,
+    /// The shards of the user and movie rows of every sample, `None` with
+    /// a single shard
+    shard_map: Option<&'a [(usize, usize)]>,
+    /// The coordinates of every sample, if known
+    matrix: Option<&'a CoordListSparseMatrix<f32>>,
     tick: Tick,
     next_ready: Tick,
+    /// When the receive port is done taking in the last sample
+    receive_ready_at: Tick,
+    /// Samples taken in whose gradients are not computed yet
+    batch: Vec<Sample>,
+    /// Number of batches computed so far
+    n_batches: usize,
 }
 
 fn can_pop(tick: Tick, fifo: &VecDeque<Sample>) -> bool {
     fifo.front().map(|s| tick >= s.time).unwrap_or(false)
 }
 
+/// The shards a sample's update goes to and the part each of them folds.
+fn update_parts(
+    shard_map: Option<&[(usize, usize)]>,
+    sample_id: usize,
+) -> Vec<(usize, UpdatePart)> {
+    match shard_map.map(|m| m[sample_id]) {
+        None => vec![(0, UpdatePart::Both)],
+        Some((row, col)) if row == col => vec![(row, UpdatePart::Both)],
+        Some((row, col)) => vec![(row, UpdatePart::Row), (col, UpdatePart::Column)],
+    }
+}
+
+/// The fold pipeline of one parameter server shard.
+#[derive(Clone, Copy, Debug, Default)]
+struct FoldUnit {
+    /// When the folding unit will be ready again
+    fold_ready_at: Tick,
+    /// When the receive port is done taking in the last batch of updates
+    receive_ready_at: Tick,
+}
+
 impl<'a> WorkerState<'a> {
-    fn new(args: &'a Args) -> Self {
+    fn new(
+        config: &'a SimConfig,
+        id: usize,
+        shard_map: Option<&'a [(usize, usize)]>,
+        matrix: Option<&'a CoordListSparseMatrix<f32>>,
+    ) -> Self {
+        // Every worker has its own stream so that they do not depend on
+        // each other's draws
+        let mut rng = stream_rng(config.seed, config.epoch, Stream::Worker(id));
+        let base_latency = config
+            .worker_gradient_latency
+            .get(id)
+            .copied()
+            .unwrap_or(config.gradient_latency);
+        let spread = config.gradient_latency_spread;
+        let scale = if spread > 0. {
+            rng.gen_range(1. - spread..=1. + spread)
+        } else {
+            1.
+        };
         Self {
-            args,
+            config,
+            id,
+            gradient_ii: config.gradient_ii_of(id),
+            gradient_latency: (base_latency as f64 * scale).round() as Tick,
+            rng,
+            uplink: DelaySampler::new(&config.uplink_delay, config.network_delay),
+            shard_map,
+            matrix,
             tick: 0,
             next_ready: 0,
+            receive_ready_at: 0,
+            batch: Vec::with_capacity(config.batch_size),
+            n_batches: 0,
         }
     }
 
-    fn ready(&self, update_tx: &VecDeque<Sample>) -> bool {
-        self.tick >= self.next_ready && update_tx.len() < self.args.fifo_depth
+    /// Latency of the next gradient, with jitter and straggling drawn.
+    fn next_gradient_latency(&mut self) -> Tick {
+        let mut latency = self.gradient_latency;
+        if self.config.straggler_prob > 0. && self.rng.gen_bool(self.config.straggler_prob) {
+            latency = (latency as f64 * self.config.straggler_slowdown).round() as Tick;
+        }
+        if self.config.gradient_jitter > 0 {
+            latency += self.rng.gen_range(0..=self.config.gradient_jitter);
+        }
+        latency
+    }
+
+    /// Whether the gradients of `samples` can start, their updates having
+    /// room in the update FIFOs of the shards they go to.
+    fn can_compute<'s>(
+        &self,
+        update_txs: &[VecDeque<Message>],
+        mut samples: impl Iterator<Item = &'s Sample>,
+    ) -> bool {
+        self.tick >= self.next_ready
+            && samples.all(|s| {
+                update_parts(self.shard_map, s.sample_id)
+                    .iter()
+                    .all(|&(shard, _)| update_txs[shard].len() < self.config.fifo_depth)
+            })
+    }
+
+    /// Number of weight rows the combined update of `batch` changes, two per
+    /// sample when their coordinates are unknown.
+    fn n_rows(&self, batch: &[Sample]) -> usize {
+        match self.matrix {
+            None => 2 * batch.len(),
+            Some(matrix) => {
+                let coords = batch.iter().map(|s| matrix[s.sample_id]);
+                let users: HashSet<usize> = coords.clone().map(|(i, _, _)| i).collect();
+                let movies: HashSet<usize> = coords.map(|(_, j, _)| j).collect();
+                users.len() + movies.len()
+            }
+        }
     }
 
-    fn tick_worker(&mut self, sample_rx: &mut VecDeque<Sample>, update_tx: &mut VecDeque<Sample>) {
-        if self.ready(update_tx) && can_pop(self.tick, sample_rx) {
-            let mut s = sample_rx.pop_front().unwrap();
-            s.time = self.tick
-                + self.args.gradient_latency
-                + self.args.network_delay
-                + self.args.send_delay;
-            update_tx.push_back(s);
-            self.next_ready = self.tick + self.args.gradient_ii;
+    /// Compute the combined update of the batch, starting `receive` ticks
+    /// from now once the last sample is received, and send it to the shards.
+    fn compute_batch(
+        &mut self,
+        receive: Tick,
+        update_txs: &mut [VecDeque<Message>],
+        trace: &mut Trace,
+    ) {
+        let batch = std::mem::take(&mut self.batch);
+        let n = batch.len() as Tick;
+        // The gradients go through the pipeline one after the other
+        let latency = self.next_gradient_latency() + (n - 1) * self.gradient_ii;
+        let network_delay = self.uplink.sample(&mut self.rng);
+        let c = Component::Worker(self.id);
+        for s in &batch {
+            let busy = receive + latency;
+            trace.record(self.tick, busy, c, EventKind::Gradient, Some(s.sample_id));
         }
+        // `send_delay` sends the two rows of a single sample's update
+        let size = self.config.update_size * self.n_rows(&batch) as f64 / 2.;
+        let send_delay = (self.config.send_delay as f64 * size).ceil() as Tick;
+        let time = self.tick + receive + latency + network_delay + send_delay;
+
+        // One message per shard with the parts of the samples it owns
+        let mut messages = vec![vec![]; self.config.n_shards];
+        for s in batch {
+            for (shard, part) in update_parts(self.shard_map, s.sample_id) {
+                messages[shard].push(Sample {
+                    time,
+                    shard,
+                    part,
+                    batch: self.n_batches,
+                    ..s
+                });
+            }
+        }
+        for (shard, updates) in messages.into_iter().enumerate() {
+            if let Some(first) = updates.first() {
+                trace.record(
+                    self.tick,
+                    0,
+                    c,
+                    EventKind::UpdateFifoPush,
+                    Some(first.sample_id),
+                );
+                update_txs[shard].push_back(Message { time, updates });
+            }
+        }
+        self.next_ready = self.tick + n * self.gradient_ii;
+        self.n_batches += 1;
+    }
+
+    /// Returns whether a sample was taken in or a batch computed. With
+    /// `flush` a partial batch is computed once no more samples are coming.
+    fn tick_worker(
+        &mut self,
+        sample_rx: &mut VecDeque<Sample>,
+        update_txs: &mut [VecDeque<Message>],
+        flush: bool,
+        trace: &mut Trace,
+    ) -> bool {
+        let received = self.tick >= self.receive_ready_at;
+        // The sample completing the batch is only taken in once its gradient
+        // can start
+        let fills = self.batch.len() + 1 == self.config.batch_size;
+        let take = received
+            && can_pop(self.tick, sample_rx)
+            && (!fills || self.can_compute(update_txs, self.batch.iter().chain(sample_rx.front())));
+        let active = if take {
+            let s = sample_rx.pop_front().unwrap();
+            let c = Component::Worker(self.id);
+            trace.record(self.tick, 0, c, EventKind::SampleFifoPop, Some(s.sample_id));
+            self.batch.push(s);
+            if fills {
+                self.compute_batch(self.config.receive_delay, update_txs, trace);
+            }
+            self.receive_ready_at = self.tick + self.config.receive_delay;
+            true
+        } else if flush
+            && received
+            && sample_rx.is_empty()
+            && !self.batch.is_empty()
+            && self.can_compute(update_txs, self.batch.iter())
+        {
+            self.compute_batch(0, update_txs, trace);
+            true
+        } else {
+            false
+        };
         self.tick += 1;
+        active
     }
 }
 
 struct ParamsServerState<'a> {
     tick: Tick,
-    args: &'a Args,
+    config: &'a SimConfig,
     num_samples: usize,
-    /// The id of the next sample to be sent
+    /// Number of updates to fold, more than `num_samples` when samples are
+    /// split over shards
+    num_updates: usize,
+    /// Sample ids in the order they are sent
+    order: Vec<usize>,
+    /// The position in `order` of the next sample to be sent
     next_sample: usize,
     curr_weight_version: usize,
-    /// When the banks will be ready to send another sample.
-    /// Earliest ready is at the front
-    bank_states: VecDeque<Tick>,
+    /// When every bank will be ready to send another sample
+    bank_free_at: Vec<Tick>,
+    /// The banks of the user and movie rows of every sample, `None` when
+    /// the banks are a pool
+    sample_banks: Option<Vec<(usize, usize)>>,
+    bank_stats: BankStats,
+    /// The last sample counted as stalled by a busy bank
+    last_stalled: Option<usize>,
     /// A new weight version to be used at a future time.
     /// Always push new versions to the back
     weight_version_queue: VecDeque<(Tick, usize)>,
-    /// When the folding unit will be ready again
-    fold_ready_at: Tick,
+    /// The fold pipeline of every shard
+    shards: Vec<FoldUnit>,
     /// The sequence of updates made to weights
     update_logs: UpdateLogs,
+    /// Picks the worker every sample is sent to
+    policy: Box<dyn DispatchPolicy>,
+    /// Number of samples sent to every worker whose update is not received
+    in_flight: Vec<usize>,
+    /// Rows and columns of the samples not yet visible in the weights
+    conflicts: Option<ConflictTracker>,
+    /// Holds back the samples the consistency model does not allow yet
+    gate: ConsistencyGate,
+    /// The shards of the user and movie rows of every sample, `None` with
+    /// a single shard
+    shard_map: Option<&'a [(usize, usize)]>,
+    /// Draws the downlink delays
+    rng: StdRng,
+    downlink: DelaySampler,
 }
 
 impl<'a> ParamsServerState<'a> {
-    fn new(args: &'a Args, num_samples: usize) -> Self {
+    fn new(
+        config: &'a SimConfig,
+        order: Vec<usize>,
+        policy: Box<dyn DispatchPolicy>,
+        shard_map: Option<&'a [(usize, usize)]>,
+    ) -> Self {
+        let num_samples = order.len();
+        let num_updates = order
+            .iter()
+            .map(|&id| update_parts(shard_map, id).len())
+            .sum();
         Self {
             tick: 0,
-            args,
+            config,
             num_samples,
+            num_updates,
+            order,
             next_sample: 0,
             curr_weight_version: 0,
-            bank_states: VecDeque::with_capacity(args.n_weight_banks),
-            weight_version_queue: VecDeque::with_capacity(args.n_folders),
-            fold_ready_at: 0,
-            update_logs: UpdateLogs::with_capacity(num_samples),
+            bank_free_at: vec![0; config.n_weight_banks],
+            sample_banks: None,
+            bank_stats: BankStats::default(),
+            last_stalled: None,
+            weight_version_queue: VecDeque::with_capacity(config.n_folders),
+            shards: vec![FoldUnit::default(); config.n_shards],
+            update_logs: UpdateLogs::with_capacity(num_updates),
+            policy,
+            in_flight: vec![0; config.n_workers],
+            conflicts: None,
+            gate: ConsistencyGate::new(config),
+            shard_map,
+            rng: stream_rng(config.seed, config.epoch, Stream::Server),
+            downlink: DelaySampler::new(&config.downlink_delay, config.network_delay),
         }
     }
 
-    fn has_free_weight_banks(&self) -> bool {
-        self.bank_states.len() < self.args.n_weight_banks
-    }
-
     fn has_more_samples(&self) -> bool {
         self.next_sample < self.num_samples
     }
 
     fn can_send(&self) -> bool {
-        self.has_more_samples() && self.has_free_weight_banks()
+        self.next_sendable().is_some_and(|offset| {
+            self.bank_reads(self.order[self.next_sample + offset])
+                .is_some()
+        })
     }
 
-    fn can_fold(&self) -> bool {
-        self.tick >= self.fold_ready_at
+    /// The banks sending `sample_id` reads with the number of rows read from
+    /// each, `None` if any of them is busy.
+    fn bank_reads(&self, sample_id: usize) -> Option<Vec<(usize, Tick)>> {
+        let free = |bank: usize| self.bank_free_at[bank] <= self.tick;
+        match &self.sample_banks {
+            None => (0..self.config.n_weight_banks)
+                .find(|&bank| free(bank))
+                .map(|bank| vec![(bank, 1)]),
+            Some(banks) => {
+                let (user, movie) = banks[sample_id];
+                let reads = if user == movie {
+                    vec![(user, 2)]
+                } else {
+                    vec![(user, 1), (movie, 1)]
+                };
+                reads.iter().all(|&(bank, _)| free(bank)).then_some(reads)
+            }
+        }
     }
 
-    fn finished_receiving(&self) -> bool {
-        self.update_logs.len() == self.num_samples
+    /// Offset from `next_sample` of the next sample to send, if any can be.
+    fn next_sendable(&self) -> Option<usize> {
+        let lookahead = self.config.conflict_lookahead;
+        let offset = match &self.conflicts {
+            Some(conflicts) if lookahead > 0 => self.order[self.next_sample..]
+                .iter()
+                .take(lookahead)
+                .position(|&id| !conflicts.has_conflicts(id)),
+            _ => self.has_more_samples().then_some(0),
+        };
+        offset.filter(|_| self.gate.allows(self.curr_weight_version))
     }
 
-    fn clear_free_banks(&mut self) {
-        while let Some(&t) = self.bank_states.front() {
-            if self.tick >= t {
-                self.bank_states.pop_front();
-            } else {
-                return;
-            }
-        }
+    fn can_fold(&self, shard: usize) -> bool {
+        self.tick >= self.shards[shard].fold_ready_at
+    }
+
+    fn can_receive(&self, shard: usize) -> bool {
+        self.tick >= self.shards[shard].receive_ready_at
+    }
+
+    fn finished_receiving(&self) -> bool {
+        self.update_logs.len() == self.num_updates
     }
 
     /// The latest weight version we know of in the future.
@@ -186,16 +758,25 @@ impl<'a> ParamsServerState<'a> {
 
     /// Add a new weight version to be incorporated in the future.
     fn push_new_weight_version(&mut self, num_updates: usize) {
-        let update_at_tick = self.tick + self.args.fold_latency;
+        let update_at_tick = self.tick + self.config.receive_delay + self.config.fold_latency;
         let new_version = self.spearhead_weight_version() + num_updates;
         self.weight_version_queue
             .push_back((update_at_tick, new_version));
     }
 
     /// Check if any weight version update should be patched.
-    fn update_weight_version(&mut self) {
+    fn update_weight_version(&mut self, trace: &mut Trace) {
         while let Some(&(t, v)) = self.weight_version_queue.front() {
             if self.tick >= t {
+                if v != self.curr_weight_version {
+                    trace.record(
+                        t,
+                        0,
+                        Component::ParamsServer,
+                        EventKind::WeightVersion,
+                        None,
+                    );
+                }
                 self.curr_weight_version = v;
                 self.weight_version_queue.pop_front();
             } else {
@@ -204,90 +785,523 @@ impl<'a> ParamsServerState<'a> {
         }
     }
 
-    fn send_next_sample(&mut self) -> Sample {
-        debug_assert!(self.has_free_weight_banks() && self.has_more_samples());
+    fn send_next_sample(&mut self, free: &[usize], trace: &mut Trace) -> (usize, Sample) {
+        debug_assert!(self.can_send());
+
+        // Move the sample sent ahead of the held back ones
+        let offset = self.next_sendable().unwrap();
+        self.order[self.next_sample..=self.next_sample + offset].rotate_right(1);
+        let sample_id = self.order[self.next_sample];
+        if let Some(conflicts) = &mut self.conflicts {
+            conflicts.stats.reordered += usize::from(offset > 0);
+            conflicts.sent(sample_id);
+        }
+        let worker = self.policy.choose(sample_id, free, &self.in_flight);
+        debug_assert!(free.contains(&worker));
+        let n_updates = update_parts(self.shard_map, sample_id).len();
+        self.gate
+            .sent(sample_id, worker, n_updates, self.curr_weight_version);
+        self.policy.sent(worker, sample_id);
+        self.in_flight[worker] += 1;
+
+        // Rows in the same bank are read one after the other
+        let reads = self.bank_reads(sample_id).unwrap();
+        let mut read_time = 0;
+        for (bank, rows) in reads {
+            let busy = rows * self.config.send_delay;
+            let c = Component::WeightBank(bank);
+            trace.record(self.tick, busy, c, EventKind::BankRead, Some(sample_id));
+            self.bank_free_at[bank] = self.tick + busy;
+            self.bank_stats.serialized += usize::from(rows > 1);
+            read_time = read_time.max(busy);
+        }
 
-        let arrival_time = self.tick + self.args.send_delay + self.args.network_delay;
+        let network_delay = self.downlink.sample(&mut self.rng);
+        let arrival_time = self.tick + read_time + network_delay;
         let sample = Sample {
             time: arrival_time,
-            sample_id: self.next_sample,
+            sample_id,
             weight_version: self.curr_weight_version,
+            worker,
+            shard: 0,
+            part: UpdatePart::Both,
+            batch: 0,
         };
 
+        let c = Component::ParamsServer;
+        let duration = read_time + network_delay;
+        trace.record(
+            self.tick,
+            duration,
+            c,
+            EventKind::Send,
+            Some(sample.sample_id),
+        );
+
         self.next_sample += 1;
-        let next_ready_at = self.tick + self.args.send_delay;
-        self.bank_states.push_back(next_ready_at);
 
-        sample
+        (worker, sample)
     }
 
-    fn try_send_samples(&mut self, sample_txs: &[VecDeque<Sample>]) -> Vec<(usize, Sample)> {
-        if !self.can_send() {
-            return vec![];
-        }
-
+    fn try_send_samples(
+        &mut self,
+        sample_txs: &[VecDeque<Sample>],
+        trace: &mut Trace,
+    ) -> Vec<(usize, Sample)> {
+        // Every worker takes at most one sample per tick
+        let mut free: Vec<usize> = (0..sample_txs.len())
+            .filter(|&i| sample_txs[i].len() < self.config.fifo_depth)
+            .collect();
         let mut res = vec![];
-        for (i, sample_tx) in sample_txs.iter().enumerate() {
-            if sample_tx.len() == self.args.fifo_depth {
-                continue;
+        loop {
+            free.retain(|&w| self.gate.accepts(w));
+            if free.is_empty() || !self.can_send() {
+                break;
             }
-            res.push((i, self.send_next_sample()));
-            if !self.can_send() {
-                return res;
+            let (worker, sample) = self.send_next_sample(&free, trace);
+            free.retain(|&w| w != worker);
+            res.push((worker, sample));
+        }
+
+        // A worker has room for the next sample but its bank is busy
+        if let (false, Some(offset)) = (free.is_empty(), self.next_sendable()) {
+            let sample_id = self.order[self.next_sample + offset];
+            if self.last_stalled != Some(sample_id) {
+                self.bank_stats.stalled += 1;
+                self.last_stalled = Some(sample_id);
             }
         }
         res
     }
 
-    fn fold_gradient(&mut self, updates: Vec<Sample>) {
-        debug_assert!(self.can_fold());
-        debug_assert!(updates.len() <= self.args.n_folders);
+    /// Fold the messages that just started arriving through the receive
+    /// port of `shard`, folding begins once they are fully received. Every
+    /// folder folds the combined update of one message.
+    fn fold_gradient(&mut self, shard: usize, messages: Vec<Message>, trace: &mut Trace) {
+        debug_assert!(self.can_fold(shard) && self.can_receive(shard));
+        debug_assert!(messages.len() <= self.config.n_folders);
+        let updates: Vec<Sample> = messages.into_iter().flat_map(|m| m.updates).collect();
+
+        if !updates.is_empty() {
+            let duration = self.config.receive_delay + self.config.fold_latency;
+            trace.record(
+                self.tick,
+                duration,
+                Component::Shard(shard),
+                EventKind::Fold,
+                None,
+            );
+        }
 
         self.push_new_weight_version(updates.len());
-        self.fold_ready_at = self.tick + self.args.fold_ii;
+        if let Some(conflicts) = &mut self.conflicts {
+            let visible_at = self.tick + self.config.receive_delay + self.config.fold_latency;
+            conflicts.folded(
+                visible_at,
+                updates.iter().map(|u| (u.sample_id, u.part)).collect(),
+            );
+        }
+        let unit = &mut self.shards[shard];
+        unit.fold_ready_at = self.tick + self.config.fold_ii;
+        if !updates.is_empty() {
+            unit.receive_ready_at = self.tick + self.config.receive_delay;
+        }
 
         for mut update in updates {
-            update.time = self.tick + self.args.fold_latency;
+            self.gate.folded(update.sample_id);
+            update.time = self.tick + self.config.receive_delay + self.config.fold_latency;
             self.update_logs.push(update);
         }
     }
 
-    fn try_receive_samples(&mut self, update_rxs: &mut [VecDeque<Sample>]) {
-        if !self.can_fold() {
-            return;
+    /// Returns whether `shard` received any update.
+    fn try_receive_samples(
+        &mut self,
+        shard: usize,
+        update_rxs: &mut [Vec<VecDeque<Message>>],
+        trace: &mut Trace,
+    ) -> bool {
+        if !self.can_fold(shard) || !self.can_receive(shard) {
+            return false;
         }
-        let mut updates = vec![];
-        for update_rx in update_rxs {
-            if can_pop(self.tick, update_rx) {
-                updates.push(update_rx.pop_front().unwrap());
+        let mut messages = vec![];
+        for (i, update_rxs) in update_rxs.iter_mut().enumerate() {
+            let update_rx = &mut update_rxs[shard];
+            if update_rx.front().is_some_and(|m| self.tick >= m.time) {
+                let message = update_rx.pop_front().unwrap();
+                for update in &message.updates {
+                    // Every sample has exactly one update with its row
+                    if update.part.has_row() {
+                        self.policy.received(i, update.sample_id);
+                        self.in_flight[i] -= 1;
+                    }
+                }
+                let c = Component::Worker(i);
+                trace.record(
+                    self.tick,
+                    0,
+                    c,
+                    EventKind::UpdateFifoPop,
+                    Some(message.updates[0].sample_id),
+                );
+                messages.push(message);
             }
         }
-        self.fold_gradient(updates);
+        let received = !messages.is_empty();
+        self.fold_gradient(shard, messages, trace);
+        received
     }
 
-    fn cleanup(&mut self) {
+    fn cleanup(&mut self, trace: &mut Trace) {
         self.tick = self.update_logs.max_time();
-        self.update_logs.sort();
 
-        self.update_weight_version();
+        self.update_weight_version(trace);
 
         assert!(
             self.weight_version_queue.is_empty()
-                && self.curr_weight_version == self.num_samples
-                && self.update_logs.len() == self.num_samples
+                && self.curr_weight_version == self.num_updates
+                && self.update_logs.len() == self.num_updates
         );
+        self.update_logs.conflicts = self.conflicts.as_ref().map(|c| c.stats);
+        self.update_logs.banks = self.bank_stats;
     }
 
     fn tick_server(
         &mut self,
         sample_txs: &[VecDeque<Sample>],
-        update_rxs: &mut [VecDeque<Sample>],
-    ) -> Vec<(usize, Sample)> {
-        self.clear_free_banks();
-        self.update_weight_version();
-        let samples = self.try_send_samples(sample_txs);
-        self.try_receive_samples(update_rxs);
+        update_rxs: &mut [Vec<VecDeque<Message>>],
+        trace: &mut Trace,
+    ) -> (Vec<(usize, Sample)>, bool) {
+        self.update_weight_version(trace);
+        if let Some(conflicts) = &mut self.conflicts {
+            conflicts.release(self.tick);
+        }
+        let samples = self.try_send_samples(sample_txs, trace);
+        let mut received = false;
+        for shard in 0..self.config.n_shards {
+            received |= self.try_receive_samples(shard, update_rxs, trace);
+        }
         self.tick += 1;
-        samples
+        (samples, received)
+    }
+
+    /// The first tick at or after `tick` where the folding unit of `shard`
+    /// takes in updates, assuming it idles until then. An idle unit still
+    /// runs empty folds every `fold_ii` ticks, which sets the ticks it is
+    /// ready at.
+    fn next_fold_at(&self, shard: usize, tick: Tick) -> Tick {
+        let unit = self.shards[shard];
+        let first = unit.fold_ready_at.max(unit.receive_ready_at);
+        if tick <= first || self.config.fold_ii == 0 {
+            return first.max(tick);
+        }
+        let ii = self.config.fold_ii;
+        first + (tick - first).div_ceil(ii) * ii
+    }
+
+    /// Jump to `tick`, doing the empty folds of the skipped ticks.
+    fn skip_to(&mut self, tick: Tick) {
+        for unit in self.shards.iter_mut() {
+            let first = unit.fold_ready_at.max(unit.receive_ready_at);
+            if first < tick && self.config.fold_ii != 0 {
+                let ii = self.config.fold_ii;
+                let last_fold = first + (tick - 1 - first) / ii * ii;
+                unit.fold_ready_at = last_fold + ii;
+            }
+        }
+        self.tick = tick;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{data_structures::CoordListSparseMatrix, delay::DelayDistribution};
+
+    fn assert_same_as_stepped(config: &SimConfig, num_samples: usize) {
+        let mut matrix = CoordListSparseMatrix::new_empty();
+        (0..7).for_each(|_| matrix.add_row());
+        (0..11).for_each(|_| matrix.add_col());
+        for i in 0..num_samples {
+            matrix.insert(i % 7, i % 11, 1.);
+        }
+        let run = |event_driven| {
+            let (policy, matrix) = (new_policy(config, Some(&matrix)), Some(&matrix));
+            let order = (0..num_samples).rev().collect();
+            let trace = Trace::disabled();
+            let (tick, logs, _) = simulate(config, order, policy, matrix, trace, event_driven);
+            (tick, logs)
+        };
+        let (event_tick, event_logs) = run(true);
+        let (step_tick, step_logs) = run(false);
+
+        assert_eq!(event_tick, step_tick, "{:?}", config);
+        assert_eq!(event_logs.len(), step_logs.len());
+        let conflicts = |logs: &UpdateLogs| logs.conflicts.map(|c| (c.conflicting, c.reordered));
+        assert_eq!(conflicts(&event_logs), conflicts(&step_logs));
+        let banks = |logs: &UpdateLogs| (logs.banks.stalled, logs.banks.serialized);
+        assert_eq!(banks(&event_logs), banks(&step_logs));
+        for (a, b) in event_logs.samples.iter().zip(&step_logs.samples) {
+            assert_eq!(
+                (a.time, a.sample_id, a.weight_version, a.worker),
+                (b.time, b.sample_id, b.weight_version, b.worker),
+                "{:?}",
+                config
+            );
+        }
+    }
+
+    #[test]
+    fn rows_sharing_a_bank_are_serialized() {
+        // Two users and two movies, memory rows 0-1 and 2-3
+        let mut matrix = CoordListSparseMatrix::new_empty();
+        (0..2).for_each(|_| matrix.add_row());
+        (0..2).for_each(|_| matrix.add_col());
+        for (i, j) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
+            matrix.insert(i, j, 1.);
+        }
+        let serialized = |bank_mapping| {
+            let config = SimConfig {
+                bank_mapping,
+                n_weight_banks: 2,
+                ..Default::default()
+            };
+            let policy = new_policy(&config, Some(&matrix));
+            let (_, logs) =
+                run_simulation_with_policy(&config, vec![0, 1, 2, 3], policy, Some(&matrix));
+            logs.banks.serialized
+        };
+        // Samples (0, 0) and (1, 1) read rows 0 and 2, 1 and 3
+        assert_eq!(serialized(BankMapping::Modulo), 2);
+        // Every sample reads a user row from bank 0 and a movie row from 1
+        assert_eq!(serialized(BankMapping::Range), 0);
+        assert_eq!(serialized(BankMapping::Pool), 0);
+    }
+
+    #[test]
+    fn batch_updates_take_longer_to_send() {
+        // Samples (0, 0), (1, 1) and (0, 1)
+        let mut matrix = CoordListSparseMatrix::new_empty();
+        (0..2).for_each(|_| matrix.add_row());
+        (0..2).for_each(|_| matrix.add_col());
+        for (i, j) in [(0, 0), (1, 1), (0, 1)] {
+            matrix.insert(i, j, 1.);
+        }
+        let config = SimConfig {
+            batch_size: 3,
+            send_delay: 10,
+            gradient_ii: 0,
+            gradient_latency: 0,
+            network_delay: 0,
+            ..Default::default()
+        };
+        let send_time = |matrix, samples: &[usize]| {
+            let mut worker = WorkerState::new(&config, 0, None, matrix);
+            worker.batch = samples
+                .iter()
+                .map(|&sample_id| Sample {
+                    time: 0,
+                    sample_id,
+                    weight_version: 0,
+                    worker: 0,
+                    shard: 0,
+                    part: UpdatePart::Both,
+                    batch: 0,
+                })
+                .collect();
+            let mut update_txs = vec![VecDeque::new()];
+            worker.compute_batch(0, &mut update_txs, &mut Trace::disabled());
+            update_txs[0][0].time
+        };
+        let matrix = Some(&matrix);
+        assert_eq!(send_time(matrix, &[0]), 10);
+        assert_eq!(send_time(matrix, &[0, 1]), 20);
+        // Sample 2 only changes rows the others change too
+        assert_eq!(send_time(matrix, &[0, 2]), 15);
+        assert_eq!(send_time(matrix, &[0, 1, 2]), 20);
+        assert_eq!(send_time(None, &[0, 1, 2]), 30);
+    }
+
+    #[test]
+    fn event_driven_matches_stepped() {
+        let default = SimConfig::default();
+        let configs = [
+            default.clone(),
+            SimConfig {
+                receive_delay: 0,
+                ..default.clone()
+            },
+            SimConfig {
+                gradient_latency: 1000,
+                network_delay: 300,
+                fold_latency: 500,
+                ..default.clone()
+            },
+            SimConfig {
+                n_workers: 3,
+                n_weight_banks: 2,
+                n_folders: 3,
+                fifo_depth: 1,
+                ..default.clone()
+            },
+            SimConfig {
+                fold_ii: 13,
+                receive_delay: 5,
+                gradient_ii: 1,
+                send_delay: 1,
+                ..default.clone()
+            },
+            SimConfig {
+                fold_ii: 0,
+                gradient_ii: 0,
+                receive_delay: 0,
+                ..default.clone()
+            },
+            SimConfig {
+                n_workers: 4,
+                worker_gradient_ii: vec![8, 8, 16, 40],
+                worker_gradient_latency: vec![32, 32, 64, 200],
+                gradient_jitter: 9,
+                straggler_prob: 0.1,
+                ..default.clone()
+            },
+            SimConfig {
+                downlink_delay: DelayModel {
+                    distribution: DelayDistribution::Uniform,
+                    spread: 6,
+                    histogram: vec![],
+                },
+                uplink_delay: DelayModel {
+                    distribution: DelayDistribution::Exponential,
+                    ..DelayModel::default()
+                },
+                network_delay: 20,
+                ..default.clone()
+            },
+            SimConfig {
+                uplink_delay: DelayModel {
+                    distribution: DelayDistribution::Empirical,
+                    spread: 0,
+                    histogram: vec![(2, 5.), (10, 3.), (300, 0.5)],
+                },
+                ..default.clone()
+            },
+            SimConfig {
+                update_size: 0.3,
+                ..default.clone()
+            },
+            SimConfig {
+                gradient_latency_spread: 0.5,
+                straggler_prob: 0.05,
+                straggler_slowdown: 10.,
+                ..default.clone()
+            },
+            SimConfig {
+                n_workers: 16,
+                n_folders: 16,
+                fold_ii: 3,
+                receive_delay: 7,
+                ..default.clone()
+            },
+        ];
+        let dispatches = [
+            DispatchStrategy::RoundRobin,
+            DispatchStrategy::LeastLoaded,
+            DispatchStrategy::Random,
+            DispatchStrategy::ConflictAware,
+        ];
+        let dispatched = dispatches.map(|dispatch| SimConfig {
+            dispatch,
+            n_workers: 5,
+            worker_gradient_latency: vec![32, 40, 100, 32, 64],
+            ..default.clone()
+        });
+        let synchronized = [
+            SimConfig {
+                consistency: Consistency::Ssp,
+                staleness_bound: 0,
+                ..default.clone()
+            },
+            SimConfig {
+                consistency: Consistency::Ssp,
+                staleness_bound: 20,
+                gradient_jitter: 20,
+                n_shards: 2,
+                ..default.clone()
+            },
+            SimConfig {
+                consistency: Consistency::Ssp,
+                staleness_bound: 0,
+                n_shards: 2,
+                ..default.clone()
+            },
+            SimConfig {
+                consistency: Consistency::Bsp,
+                batch_size: 3,
+                straggler_prob: 0.1,
+                ..default.clone()
+            },
+            SimConfig {
+                consistency: Consistency::Bsp,
+                n_shards: 2,
+                dispatch: DispatchStrategy::LeastLoaded,
+                gradient_jitter: 20,
+                ..default.clone()
+            },
+        ];
+        let held_back = [1, 4, 64].map(|conflict_lookahead| SimConfig {
+            conflict_lookahead,
+            gradient_jitter: 20,
+            ..default.clone()
+        });
+        let mapped =
+            [BankMapping::Modulo, BankMapping::Hashed, BankMapping::Range].map(|bank_mapping| {
+                SimConfig {
+                    bank_mapping,
+                    n_weight_banks: 3,
+                    ..default.clone()
+                }
+            });
+        let sharded =
+            [Partition::Modulo, Partition::Hashed, Partition::Range].map(|shard_mapping| {
+                SimConfig {
+                    n_shards: 3,
+                    shard_mapping,
+                    fold_ii: 20,
+                    conflict_lookahead: 4,
+                    ..default.clone()
+                }
+            });
+        let batched = [
+            SimConfig {
+                batch_size: 2,
+                ..default.clone()
+            },
+            SimConfig {
+                batch_size: 5,
+                gradient_jitter: 20,
+                dispatch: DispatchStrategy::LeastLoaded,
+                ..default.clone()
+            },
+            SimConfig {
+                batch_size: 4,
+                n_shards: 3,
+                conflict_lookahead: 1,
+                ..default.clone()
+            },
+        ];
+        let configs = configs
+            .into_iter()
+            .chain(dispatched)
+            .chain(held_back)
+            .chain(mapped)
+            .chain(sharded)
+            .chain(batched)
+            .chain(synchronized);
+        for config in &configs.collect::<Vec<_>>() {
+            for num_samples in [0, 1, 37, 500] {
+                assert_same_as_stepped(config, num_samples);
+            }
+        }
     }
 }
@@ -0,0 +1,207 @@
+use std::fmt;
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::simulator::Sample;
+
+/// How the learning rate of an update is scaled by its staleness.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StalenessScaling {
+    /// The same learning rate for every update
+    None,
+    /// Divided by the staleness, when there is any
+    Inverse,
+    /// Multiplied by `exp(-decay * staleness)`
+    Exponential,
+}
+
+impl StalenessScaling {
+    /// Factor the learning rate of an update with `staleness` is scaled by.
+    pub fn factor(self, staleness: usize, decay: f32) -> f32 {
+        match self {
+            StalenessScaling::None => 1.,
+            StalenessScaling::Inverse => 1. / staleness.max(1) as f32,
+            StalenessScaling::Exponential => (-decay * staleness as f32).exp(),
+        }
+    }
+}
+
+/// Staleness of an update is the number of updates folded after the weights
+/// it read and before itself, by any shard. Counts are accumulated over one
+/// or more schedules, each given in fold order.
+#[derive(Clone, Debug, Default)]
+pub struct StalenessHistogram {
+    /// Number of updates with each staleness
+    counts: Vec<usize>,
+    /// Same as `counts`, for every worker
+    per_worker: Vec<Vec<usize>>,
+    /// Same as `counts`, for every parameter server shard
+    per_shard: Vec<Vec<usize>>,
+}
+
+fn bump(counts: &mut Vec<usize>, staleness: usize) {
+    if counts.len() <= staleness {
+        counts.resize(staleness + 1, 0);
+    }
+    counts[staleness] += 1;
+}
+
+fn bump_group(groups: &mut Vec<Vec<usize>>, group: usize, staleness: usize) {
+    if groups.len() <= group {
+        groups.resize(group + 1, vec![]);
+    }
+    bump(&mut groups[group], staleness);
+}
+
+impl StalenessHistogram {
+    /// Add a schedule of updates in the order they were folded.
+    pub fn add_logs(&mut self, samples: &[Sample]) {
+        for (position, sample) in samples.iter().enumerate() {
+            let staleness = position - sample.weight_version;
+            bump(&mut self.counts, staleness);
+            bump_group(&mut self.per_worker, sample.worker, staleness);
+            bump_group(&mut self.per_shard, sample.shard, staleness);
+        }
+    }
+
+    pub fn stats(&self) -> StalenessStats {
+        StalenessStats {
+            summary: Summary::new(&self.counts),
+            histogram: self.counts.clone(),
+            per_worker: self.per_worker.iter().map(|c| Summary::new(c)).collect(),
+            per_shard: self.per_shard.iter().map(|c| Summary::new(c)).collect(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Summary {
+    pub count: usize,
+    pub mean: f64,
+    pub p50: usize,
+    pub p90: usize,
+    pub p99: usize,
+    pub max: usize,
+}
+
+impl Summary {
+    fn new(counts: &[usize]) -> Self {
+        let count: usize = counts.iter().sum();
+        let total: usize = counts.iter().enumerate().map(|(s, &c)| s * c).sum();
+        let percentile = |p: f64| {
+            let rank = ((count as f64) * p).ceil().max(1.) as usize;
+            let mut seen = 0;
+            counts
+                .iter()
+                .position(|&c| {
+                    seen += c;
+                    seen >= rank
+                })
+                .unwrap_or(0)
+        };
+        Self {
+            count,
+            mean: if count == 0 {
+                0.
+            } else {
+                total as f64 / count as f64
+            },
+            p50: percentile(0.5),
+            p90: percentile(0.9),
+            p99: percentile(0.99),
+            max: counts.iter().rposition(|&c| c > 0).unwrap_or(0),
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "mean: {:.2}, p50: {}, p90: {}, p99: {}, max: {}",
+            self.mean, self.p50, self.p90, self.p99, self.max
+        )
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct StalenessStats {
+    #[serde(flatten)]
+    pub summary: Summary,
+    /// Number of updates with each staleness, indexed by staleness
+    pub histogram: Vec<usize>,
+    /// Summary of the updates computed by each worker
+    pub per_worker: Vec<Summary>,
+    /// Summary of the updates folded by each parameter server shard
+    pub per_shard: Vec<Summary>,
+}
+
+impl StalenessStats {
+    pub fn from_logs(samples: &[Sample]) -> Self {
+        let mut histogram = StalenessHistogram::default();
+        histogram.add_logs(samples);
+        histogram.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::UpdatePart;
+
+    fn update(weight_version: usize, worker: usize) -> Sample {
+        Sample {
+            time: 0,
+            sample_id: 0,
+            weight_version,
+            worker,
+            shard: 0,
+            part: UpdatePart::Both,
+            batch: 0,
+        }
+    }
+
+    #[test]
+    fn percentiles_of_counts() {
+        // Staleness 0 five times, 1 three times and 3 twice
+        let summary = Summary::new(&[5, 3, 0, 2]);
+        assert_eq!(summary.count, 10);
+        assert!((summary.mean - 0.9).abs() < 1e-12);
+        assert_eq!(
+            (summary.p50, summary.p90, summary.p99, summary.max),
+            (0, 3, 3, 3)
+        );
+        let summary = Summary::new(&[5, 5]);
+        assert_eq!((summary.p50, summary.p90), (0, 1));
+    }
+
+    #[test]
+    fn no_updates() {
+        let summary = Summary::new(&[]);
+        assert_eq!((summary.count, summary.p50, summary.max), (0, 0, 0));
+        assert_eq!(summary.mean, 0.);
+    }
+
+    #[test]
+    fn staleness_counts_updates_folded_in_between() {
+        // The third update read the weights before both others were folded
+        let logs = [update(0, 0), update(1, 1), update(0, 1)];
+        let stats = StalenessStats::from_logs(&logs);
+        assert_eq!(stats.histogram, vec![2, 0, 1]);
+        assert_eq!(stats.per_worker[0].max, 0);
+        assert_eq!(stats.per_worker[1].max, 2);
+        assert_eq!(stats.per_shard[0].count, 3);
+    }
+
+    #[test]
+    fn scaling_factors() {
+        assert_eq!(StalenessScaling::None.factor(7, 0.1), 1.);
+        assert_eq!(StalenessScaling::Inverse.factor(0, 0.1), 1.);
+        assert_eq!(StalenessScaling::Inverse.factor(4, 0.1), 0.25);
+        assert_eq!(StalenessScaling::Exponential.factor(0, 0.1), 1.);
+        let factor = StalenessScaling::Exponential.factor(10, 0.1);
+        assert!((factor - (-1f32).exp()).abs() < 1e-6);
+    }
+}
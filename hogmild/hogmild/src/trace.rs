@@ -0,0 +1,162 @@
+use std::{fs::File, io, io::BufWriter, path::Path};
+
+use serde::Serialize;
+
+use crate::simulator::Tick;
+
+/// Part of the simulated hardware an event happened in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Component {
+    ParamsServer,
+    Worker(usize),
+    WeightBank(usize),
+    /// The fold pipeline of a parameter server shard
+    Shard(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// The server starts sending a sample to a worker
+    Send,
+    /// A weight bank starts reading the rows of a sample being sent
+    BankRead,
+    /// A sample enters a worker's sample FIFO
+    SampleFifoPush,
+    /// A worker takes a sample out of its sample FIFO
+    SampleFifoPop,
+    /// A worker starts receiving a sample and calculating its gradient
+    Gradient,
+    /// A worker's update enters its update FIFO
+    UpdateFifoPush,
+    /// The server takes an update out of a worker's update FIFO
+    UpdateFifoPop,
+    /// The server starts receiving and folding a batch of updates
+    Fold,
+    /// A new weight version becomes visible to samples being sent
+    WeightVersion,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TraceEvent {
+    pub tick: Tick,
+    /// Number of ticks the event lasts, 0 for instant events
+    pub duration: Tick,
+    pub component: Component,
+    pub kind: EventKind,
+    /// The sample the event is about, if any
+    pub sample_id: Option<usize>,
+}
+
+/// Every event of a simulation, in the order they happened. Recording is a
+/// no-op unless the trace is enabled.
+#[derive(Debug, Default)]
+pub struct Trace {
+    enabled: bool,
+    pub events: Vec<TraceEvent>,
+}
+
+/// One entry of the Chrome trace event format, also read by Perfetto.
+#[derive(Serialize)]
+struct ChromeEvent {
+    name: String,
+    ph: &'static str,
+    ts: Tick,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dur: Option<Tick>,
+    pid: usize,
+    tid: usize,
+    #[serde(skip_serializing_if = "serde_json::Value::is_null")]
+    args: serde_json::Value,
+}
+
+impl Component {
+    fn tid(self) -> usize {
+        match self {
+            Component::ParamsServer => 0,
+            Component::Worker(i) => i + 1,
+            // After any realistic number of workers
+            Component::WeightBank(i) => i + 1_000_000,
+            Component::Shard(i) => i + 2_000_000,
+        }
+    }
+
+    fn name(self) -> String {
+        match self {
+            Component::ParamsServer => "params server".to_string(),
+            Component::Worker(i) => format!("worker {}", i),
+            Component::WeightBank(i) => format!("weight bank {}", i),
+            Component::Shard(i) => format!("params server shard {}", i),
+        }
+    }
+}
+
+impl Trace {
+    pub fn enabled() -> Self {
+        Self {
+            enabled: true,
+            events: vec![],
+        }
+    }
+
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    pub fn record(
+        &mut self,
+        tick: Tick,
+        duration: Tick,
+        component: Component,
+        kind: EventKind,
+        sample_id: Option<usize>,
+    ) {
+        if self.enabled {
+            self.events.push(TraceEvent {
+                tick,
+                duration,
+                component,
+                kind,
+                sample_id,
+            });
+        }
+    }
+
+    /// Write the events in the Chrome trace event format, one tick per
+    /// microsecond and one track per component.
+    pub fn write_chrome_json(&self, path: &Path) -> io::Result<()> {
+        let mut components: Vec<Component> = self.events.iter().map(|e| e.component).collect();
+        components.sort_by_key(|c| c.tid());
+        components.dedup();
+
+        let names = components.into_iter().map(|c| ChromeEvent {
+            name: "thread_name".to_string(),
+            ph: "M",
+            ts: 0,
+            dur: None,
+            pid: 0,
+            tid: c.tid(),
+            args: serde_json::json!({ "name": c.name() }),
+        });
+        let events = self.events.iter().map(|e| ChromeEvent {
+            name: serde_json::to_value(e.kind)
+                .unwrap()
+                .as_str()
+                .unwrap()
+                .to_string(),
+            ph: if e.duration == 0 { "i" } else { "X" },
+            ts: e.tick,
+            dur: (e.duration != 0).then_some(e.duration),
+            pid: 0,
+            tid: e.component.tid(),
+            args: match e.sample_id {
+                Some(id) => serde_json::json!({ "sample_id": id }),
+                None => serde_json::Value::Null,
+            },
+        });
+
+        let writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(writer, &names.chain(events).collect::<Vec<_>>())?;
+        Ok(())
+    }
+}
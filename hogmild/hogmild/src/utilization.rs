@@ -0,0 +1,196 @@
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::{
+    simulator::{SimConfig, Tick},
+    trace::{Component, EventKind, Trace},
+};
+
+/// How busy one piece of hardware, or the average of a group of them, was.
+#[derive(Clone, Debug, Serialize)]
+pub struct ResourceUsage {
+    pub name: String,
+    pub busy_ticks: Tick,
+    /// Fraction of the simulated ticks the resource was busy
+    pub utilization: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct FifoUsage {
+    pub name: String,
+    /// Average number of entries over time
+    pub mean_occupancy: f64,
+    pub max_occupancy: usize,
+    /// Fraction of the simulated ticks the FIFO was full
+    pub full_fraction: f64,
+}
+
+/// Busy and idle time of the simulated hardware, derived from a trace.
+#[derive(Clone, Debug, Serialize)]
+pub struct UtilizationReport {
+    pub total_ticks: Tick,
+    /// Every weight bank, the fold unit and receive port of every server
+    /// shard and every worker's gradient pipeline
+    pub resources: Vec<ResourceUsage>,
+    /// Averages over the weight banks, the fold units, the receive ports
+    /// and the workers
+    pub groups: Vec<ResourceUsage>,
+    pub fifos: Vec<FifoUsage>,
+    /// The busiest group, which limits the throughput
+    pub bottleneck: String,
+}
+
+fn usage(name: String, busy_ticks: Tick, units: usize, total_ticks: Tick) -> ResourceUsage {
+    let capacity = (total_ticks.max(1) * units as Tick) as f64;
+    ResourceUsage {
+        name,
+        busy_ticks,
+        utilization: busy_ticks as f64 / capacity,
+    }
+}
+
+/// Occupancy over time of a FIFO given its `(tick, +1 or -1)` changes.
+fn fifo_usage(name: String, mut changes: Vec<(Tick, i64)>, depth: usize, total: Tick) -> FifoUsage {
+    changes.sort_by_key(|&(t, _)| t);
+    let (mut occupancy, mut max_occupancy) = (0i64, 0i64);
+    let (mut area, mut full_ticks, mut last) = (0i64, 0, 0);
+    for (t, delta) in changes {
+        area += occupancy * (t - last) as i64;
+        if occupancy as usize >= depth {
+            full_ticks += t - last;
+        }
+        last = t;
+        occupancy += delta;
+        max_occupancy = max_occupancy.max(occupancy);
+    }
+    let total = total.max(1);
+    FifoUsage {
+        name,
+        mean_occupancy: area as f64 / total as f64,
+        max_occupancy: max_occupancy as usize,
+        full_fraction: full_ticks as f64 / total as f64,
+    }
+}
+
+impl UtilizationReport {
+    pub fn from_trace(trace: &Trace, config: &SimConfig, total_ticks: Tick) -> Self {
+        let n_workers = config.n_workers;
+        let mut bank_busy = vec![0; config.n_weight_banks];
+        let mut worker_busy = vec![0; n_workers];
+        let n_shards = config.n_shards;
+        let (mut fold_busy, mut receive_busy) = (vec![0; n_shards], vec![0; n_shards]);
+        let mut sample_fifos = vec![vec![]; n_workers];
+        let mut update_fifos = vec![vec![]; n_workers];
+
+        for e in &trace.events {
+            let worker = match e.component {
+                Component::Worker(i) | Component::WeightBank(i) | Component::Shard(i) => i,
+                Component::ParamsServer => 0,
+            };
+            match e.kind {
+                EventKind::Send => {}
+                EventKind::BankRead => bank_busy[worker] += e.duration,
+                EventKind::Gradient => worker_busy[worker] += config.gradient_ii_of(worker),
+                EventKind::Fold => {
+                    fold_busy[worker] += config.fold_ii;
+                    receive_busy[worker] += config.receive_delay;
+                }
+                EventKind::SampleFifoPush => sample_fifos[worker].push((e.tick, 1)),
+                EventKind::SampleFifoPop => sample_fifos[worker].push((e.tick, -1)),
+                EventKind::UpdateFifoPush => update_fifos[worker].push((e.tick, 1)),
+                EventKind::UpdateFifoPop => update_fifos[worker].push((e.tick, -1)),
+                EventKind::WeightVersion => {}
+            }
+        }
+
+        let mut resources = vec![];
+        for (i, &busy) in bank_busy.iter().enumerate() {
+            resources.push(usage(format!("weight bank {}", i), busy, 1, total_ticks));
+        }
+        // Shards are only numbered when there are several
+        let shard_name = |name: &str, i: usize| match n_shards {
+            1 => name.to_string(),
+            _ => format!("{} {}", name, i),
+        };
+        for (i, &busy) in fold_busy.iter().enumerate() {
+            resources.push(usage(shard_name("fold unit", i), busy, 1, total_ticks));
+        }
+        for (i, &busy) in receive_busy.iter().enumerate() {
+            resources.push(usage(shard_name("receive port", i), busy, 1, total_ticks));
+        }
+        for (i, &busy) in worker_busy.iter().enumerate() {
+            resources.push(usage(format!("worker {}", i), busy, 1, total_ticks));
+        }
+
+        let groups = vec![
+            usage(
+                "weight banks".to_string(),
+                bank_busy.iter().sum(),
+                config.n_weight_banks,
+                total_ticks,
+            ),
+            usage(
+                "fold unit".to_string(),
+                fold_busy.iter().sum(),
+                n_shards,
+                total_ticks,
+            ),
+            usage(
+                "receive port".to_string(),
+                receive_busy.iter().sum(),
+                n_shards,
+                total_ticks,
+            ),
+            usage(
+                "workers".to_string(),
+                worker_busy.iter().sum(),
+                n_workers,
+                total_ticks,
+            ),
+        ];
+        let bottleneck = groups
+            .iter()
+            .max_by(|a, b| a.utilization.total_cmp(&b.utilization))
+            .unwrap()
+            .name
+            .clone();
+
+        let depth = config.fifo_depth;
+        let mut fifos = vec![];
+        for (i, changes) in sample_fifos.into_iter().enumerate() {
+            let name = format!("worker {} samples", i);
+            fifos.push(fifo_usage(name, changes, depth, total_ticks));
+        }
+        for (i, changes) in update_fifos.into_iter().enumerate() {
+            let name = format!("worker {} updates", i);
+            fifos.push(fifo_usage(name, changes, depth, total_ticks));
+        }
+
+        Self {
+            total_ticks,
+            resources,
+            groups,
+            fifos,
+            bottleneck,
+        }
+    }
+}
+
+impl fmt::Display for UtilizationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Resource,BusyTicks,Utilization")?;
+        for r in self.resources.iter().chain(&self.groups) {
+            writeln!(f, "{},{},{:.3}", r.name, r.busy_ticks, r.utilization)?;
+        }
+        writeln!(f, "Fifo,MeanOccupancy,MaxOccupancy,FullFraction")?;
+        for q in &self.fifos {
+            writeln!(
+                f,
+                "{},{:.3},{},{:.3}",
+                q.name, q.mean_occupancy, q.max_occupancy, q.full_fraction
+            )?;
+        }
+        writeln!(f, "bottleneck: {}", self.bottleneck)
+    }
+}